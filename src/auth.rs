@@ -0,0 +1,113 @@
+//! Getting Dropbox credentials, and storing them in a file between runs.
+
+use anyhow::{anyhow, Context, Result};
+use dropbox_sdk::default_client::NoauthDefaultClient;
+use dropbox_sdk::oauth2::{Authorization, AuthorizeUrlBuilder, Oauth2Type, PkceCode};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Saved credentials: the app key is needed along with the token to be able to refresh it.
+pub struct SavedAuth {
+    pub client_id: String,
+    pub auth: Authorization,
+}
+
+fn prompt(msg: &str) -> Result<String> {
+    eprint!("{}: ", msg);
+    io::stderr().flush()?;
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input)? == 0 {
+        return Err(anyhow!("no credentials saved or in the environment, and no input to ask for them"));
+    }
+    Ok(input.trim().to_owned())
+}
+
+/// Where credentials are kept if no other file is given: `legacy-paper-dump/token` in the user's
+/// config folder.
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME").filter(|dir| !dir.is_empty())?).join(".config"),
+    };
+    Some(config_dir.join("legacy-paper-dump").join("token"))
+}
+
+/// Credentials given in the environment, the same ones the Dropbox SDK looks for: either a
+/// long-lived token in `DBX_OAUTH_TOKEN`, or an app key and saved token in `DBX_CLIENT_ID` and
+/// `DBX_OAUTH`.
+pub fn from_env() -> Option<Authorization> {
+    if let Ok(token) = env::var("DBX_OAUTH_TOKEN") {
+        return Some(Authorization::from_access_token(token));
+    }
+    let client_id = env::var("DBX_CLIENT_ID").ok()?;
+    let saved = env::var("DBX_OAUTH").ok()?;
+    Authorization::load(client_id, &saved)
+}
+
+/// Run the OAuth2 flow interactively, always prompting even if there are credentials in the
+/// environment, and get a token.
+pub fn authorize_interactively() -> Result<SavedAuth> {
+    let client_id = prompt("Give me a Dropbox API app key")?;
+
+    let oauth2_flow = Oauth2Type::PKCE(PkceCode::new());
+    let url = AuthorizeUrlBuilder::new(&client_id, &oauth2_flow).build();
+    eprintln!("Open this URL in your browser:");
+    eprintln!("{}", url);
+    eprintln!();
+    let auth_code = prompt("Then paste the code here")?;
+
+    let mut auth = Authorization::from_auth_code(client_id.clone(), oauth2_flow, auth_code, None);
+    auth.obtain_access_token(NoauthDefaultClient::default())
+        .context("failed to obtain an access token")?;
+    Ok(SavedAuth { client_id, auth })
+}
+
+/// Load credentials previously written by [`save`]. Returns `None` if the file doesn't exist.
+pub fn load(path: &Path) -> Result<Option<SavedAuth>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+    let mut lines = contents.lines();
+    let (client_id, saved) = match (lines.next(), lines.next()) {
+        (Some(id), Some(saved)) => (id.to_owned(), saved),
+        _ => return Err(anyhow!("{} is not a valid auth file", path.display())),
+    };
+    let auth = Authorization::load(client_id.clone(), saved)
+        .ok_or_else(|| anyhow!("{} has unrecognized credentials in it", path.display()))?;
+    Ok(Some(SavedAuth { client_id, auth }))
+}
+
+/// Write credentials to a file only readable by the current user, replacing whatever was there.
+pub fn save(path: &Path, saved: &SavedAuth) -> Result<()> {
+    let token = saved.auth.save()
+        .ok_or_else(|| anyhow!("authorization hasn't completed; nothing to save"))?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+    let mut file = opts.open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        // The mode above only applies if the file is newly created.
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("failed to set permissions on {}", path.display()))?;
+    }
+    writeln!(file, "{}\n{}", saved.client_id, token)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
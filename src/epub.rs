@@ -0,0 +1,253 @@
+//! Packaging a doc and its images as an EPUB 3 book.
+
+use crate::zip::ZipWriter;
+use regex::{Captures, Regex};
+use std::io::{self, Write};
+use std::sync::OnceLock;
+
+pub struct EpubDoc<'a> {
+    /// Unique identifier for the book; the doc's URL.
+    pub identifier: &'a str,
+    pub title: &'a str,
+    pub author: &'a str,
+    /// The doc's HTML, as a fragment to go inside `<body>`.
+    pub body: &'a str,
+    /// Images referenced by the body: the `src` used in the body, and the image data.
+    pub images: Vec<(String, Vec<u8>)>,
+    /// When the book was made. EPUB requires a modification date, so without one a fixed date is
+    /// used, to keep the output the same from run to run.
+    pub modified: Option<chrono::DateTime<chrono::Utc>>,
+    /// Give the files in the zip UTC times instead of local ones.
+    pub utc: bool,
+}
+
+/// Escape text for use in XML content or attribute values.
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Figure out an image's type from its data, since the filenames often lack an extension.
+/// Returns `None` if it's not something recognizable.
+fn image_type(data: &[u8]) -> Option<(&'static str, &'static str)> {
+    let start = String::from_utf8_lossy(&data[.. data.len().min(1024)]);
+    Some(if data.starts_with(b"\x89PNG") {
+        ("image/png", "png")
+    } else if data.starts_with(&[0xFF, 0xD8]) {
+        ("image/jpeg", "jpg")
+    } else if data.starts_with(b"GIF8") {
+        ("image/gif", "gif")
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        ("image/webp", "webp")
+    } else if data.len() >= 12 && &data[4..8] == b"ftyp"
+        && (&data[8..12] == b"avif" || &data[8..12] == b"avis")
+    {
+        ("image/avif", "avif")
+    } else if data.starts_with(b"BM") {
+        ("image/bmp", "bmp")
+    } else if data.starts_with(b"II*\0") || data.starts_with(b"MM\0*") {
+        ("image/tiff", "tiff")
+    } else if start.contains("<svg") {
+        ("image/svg+xml", "svg")
+    } else {
+        return None;
+    })
+}
+
+/// Named entities that show up in HTML but which XML doesn't know about.
+pub const ENTITIES: &[(&str, u32)] = &[
+    ("nbsp", 160), ("iexcl", 161), ("copy", 169), ("laquo", 171), ("reg", 174), ("deg", 176),
+    ("middot", 183), ("raquo", 187), ("times", 215), ("ndash", 8211), ("mdash", 8212),
+    ("lsquo", 8216), ("rsquo", 8217), ("ldquo", 8220), ("rdquo", 8221), ("bull", 8226),
+    ("hellip", 8230), ("trade", 8482), ("larr", 8592), ("rarr", 8594),
+];
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source",
+    "track", "wbr",
+];
+
+/// Make entity references XML-safe: translate HTML-only named entities to numeric ones, and
+/// escape ampersands that aren't part of an entity at all.
+fn fix_entities(s: &str) -> String {
+    static ENTITY_RE: OnceLock<Regex> = OnceLock::new();
+    let entity_re = ENTITY_RE.get_or_init(|| {
+        Regex::new(r"&(#[0-9]+;|#[xX][0-9a-fA-F]+;|([a-zA-Z][a-zA-Z0-9]*);)?").unwrap()
+    });
+    entity_re.replace_all(s, |c: &Captures<'_>| {
+        match (c.get(1), c.get(2)) {
+            (Some(_), None) => c[0].to_owned(), // numeric
+            (Some(_), Some(name)) => match name.as_str() {
+                "amp" | "lt" | "gt" | "quot" | "apos" => c[0].to_owned(),
+                name => match ENTITIES.iter().find(|(n, _)| *n == name) {
+                    Some((_, code)) => format!("&#{};", code),
+                    None => format!("&amp;{};", name),
+                },
+            },
+            _ => "&amp;".to_owned(),
+        }
+    }).into_owned()
+}
+
+/// Tidy up Paper's HTML enough to be well-formed XHTML: drop the document-level wrapper, comments
+/// and scripts, close void elements, expand minimized attributes, and fix up entities. `srcset`s
+/// are dropped too, since only the images in `src` make it into the book.
+pub fn to_xhtml(html: &str) -> String {
+    static STRIP_RE: OnceLock<Regex> = OnceLock::new();
+    static TAG_RE: OnceLock<Regex> = OnceLock::new();
+    static BOOL_ATTR_RE: OnceLock<Regex> = OnceLock::new();
+    static SRCSET_RE: OnceLock<Regex> = OnceLock::new();
+    let strip_re = STRIP_RE.get_or_init(|| {
+        Regex::new(r"(?is)<head\b.*?</head\s*>|<script\b.*?</script\s*>|<!--.*?-->|<!DOCTYPE[^>]*>")
+            .unwrap()
+    });
+    let tag_re = TAG_RE.get_or_init(|| Regex::new(r"<(/?)\s*([a-zA-Z][a-zA-Z0-9]*)([^>]*)>").unwrap());
+    let bool_attr_re = BOOL_ATTR_RE.get_or_init(|| {
+        Regex::new(r"(\s)(checked|disabled|selected|readonly|multiple|hidden)(\s|/|$)").unwrap()
+    });
+    let srcset_re = SRCSET_RE.get_or_init(|| {
+        Regex::new(r#"(?i)\ssrcset\s*=\s*(?:"[^"]*"|'[^']*'|[^\s"'>]+)"#).unwrap()
+    });
+
+    let html = strip_re.replace_all(html, "");
+    let mut out = String::with_capacity(html.len());
+    let mut last_end = 0;
+    for c in tag_re.captures_iter(&html) {
+        let m = c.get(0).unwrap();
+        out += &fix_entities(&html[last_end .. m.start()]);
+        last_end = m.end();
+
+        let closing = !c[1].is_empty();
+        let name = c[2].to_ascii_lowercase();
+        if name == "html" || name == "body" {
+            continue;
+        }
+        let attrs = srcset_re.replace_all(&c[3], "");
+        let mut attrs = bool_attr_re.replace_all(&attrs, r#"$1$2="$2"$3"#).into_owned();
+        if closing {
+            out += &format!("</{}>", name);
+            continue;
+        }
+        let self_closed = attrs.trim_end().ends_with('/');
+        if self_closed {
+            attrs = attrs.trim_end().trim_end_matches('/').to_owned();
+        }
+        out += &format!("<{}{}", name, fix_entities(&attrs));
+        if self_closed || VOID_ELEMENTS.contains(&name.as_str()) {
+            out += " />";
+        } else {
+            out += ">";
+        }
+    }
+    out += &fix_entities(&html[last_end ..]);
+    out
+}
+
+/// Write the doc out as an EPUB file, returning the underlying writer.
+pub fn write<W: Write>(out: W, doc: &EpubDoc<'_>) -> io::Result<W> {
+    let mut zip = ZipWriter::new(out, doc.modified.map(|t| if doc.utc {
+        t.naive_utc()
+    } else {
+        t.with_timezone(&chrono::Local).naive_local()
+    }));
+
+    // This has to come first, uncompressed, so readers can identify the file.
+    zip.add("mimetype", b"application/epub+zip")?;
+
+    zip.add("META-INF/container.xml", br#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles>
+    <rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/>
+  </rootfiles>
+</container>
+"#)?;
+
+    // Give the images simple names inside the book, and point the body at them.
+    let mut body = doc.body.to_owned();
+    let mut manifest = String::new();
+    for (i, (src, data)) in doc.images.iter().enumerate() {
+        // Anything else might not be an image at all, so it's left out.
+        let (media_type, ext) = match image_type(data) {
+            Some(t) => t,
+            None => continue,
+        };
+        let name = format!("images/{}.{}", i, ext);
+        body = body.replace(&format!("src=\"{}\"", src), &format!("src=\"{}\"", name));
+        manifest += &format!("    <item id=\"img{}\" href=\"{}\" media-type=\"{}\"/>\n",
+            i, name, media_type);
+        zip.add(&format!("OEBPS/{}", name), data)?;
+    }
+
+    let title = escape(doc.title);
+    zip.add("OEBPS/content.opf", format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">{id}</dc:identifier>
+    <dc:title>{title}</dc:title>
+    <dc:creator>{author}</dc:creator>
+    <dc:language>en</dc:language>
+    <meta property="dcterms:modified">{modified}</meta>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="doc" href="doc.xhtml" media-type="application/xhtml+xml"/>
+{manifest}  </manifest>
+  <spine>
+    <itemref idref="doc"/>
+  </spine>
+</package>
+"#,
+        id = escape(doc.identifier),
+        title = title,
+        author = escape(doc.author),
+        modified = doc.modified
+            .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_else(|| "2000-01-01T00:00:00Z".to_owned()),
+        manifest = manifest).as_bytes())?;
+
+    zip.add("OEBPS/nav.xhtml", format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>{title}</title></head>
+<body>
+  <nav epub:type="toc"><ol><li><a href="doc.xhtml">{title}</a></li></ol></nav>
+</body>
+</html>
+"#, title = title).as_bytes())?;
+
+    zip.add("OEBPS/doc.xhtml", format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>{title}</title></head>
+<body>
+{body}
+</body>
+</html>
+"#, title = title, body = to_xhtml(&body)).as_bytes())?;
+
+    zip.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_xhtml_drops_srcset() {
+        assert_eq!(to_xhtml(r#"<p><img src="images/a.png" srcset="../images/b.png 2x" alt="x"></p>"#),
+            r#"<p><img src="images/a.png" alt="x" /></p>"#);
+    }
+
+    #[test]
+    fn image_types() {
+        assert_eq!(image_type(b"\x89PNG\r\n\x1a\n").unwrap().0, "image/png");
+        assert_eq!(image_type(b"BM\x36\x00\x00\x00").unwrap().0, "image/bmp");
+        assert_eq!(image_type(b"II*\0\x08\0\0\0").unwrap().0, "image/tiff");
+        assert_eq!(image_type(b"\0\0\0\x1cftypavif\0\0\0\0").unwrap().0, "image/avif");
+        assert_eq!(image_type(b"<?xml version=\"1.0\"?>\n<svg xmlns=\"x\"/>").unwrap().0,
+            "image/svg+xml");
+        assert_eq!(image_type(b"<html><body>not found</body></html>"), None);
+    }
+}
@@ -0,0 +1,402 @@
+//! A small gzip compressor: LZ77 matching with deflate's fixed Huffman codes. It doesn't compress
+//! as well as zlib's dynamic codes, but HTML is repetitive enough that it still does well, and it
+//! keeps this small enough to not bother with a dependency. There's a decompressor too, for
+//! reading docs back in; it handles any gzip file, not just the ones written here.
+
+use crate::zip::crc32;
+use std::io;
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash to try before settling for the best so far.
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Writes bits least significant first, the way deflate packs them.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes go most significant bit first, unlike everything else.
+    fn code(&mut self, code: u32, len: u32) {
+        self.bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+
+    /// A literal byte, or the end-of-block marker or a length code (256 and up).
+    fn symbol(&mut self, sym: u16) {
+        let sym = sym as u32;
+        match sym {
+            0 ..= 143 => self.code(0x30 + sym, 8),
+            144 ..= 255 => self.code(0x190 + sym - 144, 9),
+            256 ..= 279 => self.code(sym - 256, 7),
+            _ => self.code(0xC0 + sym - 280, 8),
+        }
+    }
+
+    fn matched(&mut self, len: usize, dist: usize) {
+        let i = LEN_BASE.iter().rposition(|&base| base as usize <= len).unwrap();
+        self.symbol(257 + i as u16);
+        self.bits((len - LEN_BASE[i] as usize) as u32, LEN_EXTRA[i] as u32);
+        let i = DIST_BASE.iter().rposition(|&base| base as usize <= dist).unwrap();
+        self.code(i as u32, 5);
+        self.bits((dist - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i] as u32);
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let v = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (v.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compress data into a complete gzip file.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter { out: vec![], bits: 0, count: 0 };
+    // Header: magic, deflate, no flags, no timestamp, no extra flags, unknown OS.
+    w.out.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]);
+
+    // One final block, with the fixed codes.
+    w.bits(1, 1);
+    w.bits(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW];
+    let insert = |head: &mut Vec<usize>, prev: &mut Vec<usize>, pos: usize| {
+        if pos + MIN_MATCH <= data.len() {
+            let h = hash(&data[pos ..]);
+            prev[pos % WINDOW] = head[h];
+            head[h] = pos;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let max = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash(&data[pos ..])];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= WINDOW && chain < MAX_CHAIN {
+                let len = data[candidate ..].iter().zip(&data[pos .. pos + max])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best.0 {
+                    best = (len, pos - candidate);
+                    if len == max {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW];
+                // Chains only go backwards; anything else is a stale entry from a wrapped window.
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best.0 >= MIN_MATCH {
+            w.matched(best.0, best.1);
+            for p in pos .. pos + best.0 {
+                insert(&mut head, &mut prev, p);
+            }
+            pos += best.0;
+        } else {
+            w.symbol(data[pos] as u16);
+            insert(&mut head, &mut prev, pos);
+            pos += 1;
+        }
+    }
+    w.symbol(256);
+
+    let mut out = w.finish();
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt gzip data: {}", what))
+}
+
+/// Reads bits least significant first, the way deflate packs them.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> io::Result<u32> {
+        while self.count < count {
+            let byte = *self.data.get(self.pos).ok_or_else(|| corrupt("unexpected end"))?;
+            self.pos += 1;
+            self.bits |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1u64 << count) - 1) as u32;
+        self.bits >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Skip to the next byte boundary, for stored blocks.
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the symbols in code order.
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1 .. 15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = sym as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Self { counts, symbols }
+    }
+
+    /// Codes go most significant bit first, so they're read one bit at a time.
+    fn decode(&self, r: &mut BitReader<'_>) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1 .. 16 {
+            code |= r.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt("bad Huffman code"))
+    }
+}
+
+/// The order the lengths of the code length code come in.
+const CODE_LENGTH_ORDER: [usize; 19] =
+    [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// The Huffman codes of a block with dynamic codes, which come at its start.
+fn dynamic_codes(r: &mut BitReader<'_>) -> io::Result<(Huffman, Huffman)> {
+    let lit_count = r.bits(5)? as usize + 257;
+    let dist_count = r.bits(5)? as usize + 1;
+    let code_len_count = r.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[.. code_len_count] {
+        code_lengths[i] = r.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(lit_count + dist_count);
+    while lengths.len() < lit_count + dist_count {
+        let (len, repeat) = match code_length_code.decode(r)? {
+            sym @ 0 ..= 15 => (sym as u8, 1),
+            16 => (*lengths.last().ok_or_else(|| corrupt("repeat with nothing before"))?,
+                3 + r.bits(2)?),
+            17 => (0, 3 + r.bits(3)?),
+            _ => (0, 11 + r.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(len, repeat as usize));
+    }
+    if lengths.len() > lit_count + dist_count {
+        return Err(corrupt("too many code lengths"));
+    }
+    Ok((Huffman::new(&lengths[.. lit_count]), Huffman::new(&lengths[lit_count ..])))
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[.. 144].fill(8);
+    lengths[144 .. 256].fill(9);
+    lengths[256 .. 280].fill(7);
+    lengths[280 ..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+/// Decompress a complete gzip file, checking its CRC.
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < 18 || data[.. 3] != [0x1f, 0x8b, 8] {
+        return Err(corrupt("not gzip"));
+    }
+    let flags = data[3];
+    let mut pos = 10;
+    let skip_string = |pos: usize| -> io::Result<usize> {
+        let len = data.get(pos ..).and_then(|rest| rest.iter().position(|&b| b == 0))
+            .ok_or_else(|| corrupt("unterminated header field"))?;
+        Ok(pos + len + 1)
+    };
+    if flags & 4 != 0 {
+        let len = data.get(pos .. pos + 2).ok_or_else(|| corrupt("unexpected end"))?;
+        pos += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    if flags & 8 != 0 {
+        pos = skip_string(pos)?; // file name
+    }
+    if flags & 16 != 0 {
+        pos = skip_string(pos)?; // comment
+    }
+    if flags & 2 != 0 {
+        pos += 2; // header CRC
+    }
+
+    let mut r = BitReader { data, pos, bits: 0, count: 0 };
+    let mut out = vec![];
+    loop {
+        let last = r.bits(1)? == 1;
+        let (lit, dist) = match r.bits(2)? {
+            0 => {
+                r.align();
+                let header = data.get(r.pos .. r.pos + 4).ok_or_else(|| corrupt("unexpected end"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(corrupt("bad stored block length"));
+                }
+                let start = r.pos + 4;
+                let block = data.get(start .. start + len as usize)
+                    .ok_or_else(|| corrupt("unexpected end"))?;
+                out.extend_from_slice(block);
+                r.pos = start + len as usize;
+                if last {
+                    break;
+                }
+                continue;
+            }
+            1 => fixed_codes(),
+            2 => dynamic_codes(&mut r)?,
+            _ => return Err(corrupt("bad block type")),
+        };
+        loop {
+            let sym = lit.decode(&mut r)? as usize;
+            match sym {
+                0 ..= 255 => out.push(sym as u8),
+                256 => break,
+                257 ..= 285 => {
+                    let i = sym - 257;
+                    let len = LEN_BASE[i] as usize + r.bits(LEN_EXTRA[i] as u32)? as usize;
+                    let i = dist.decode(&mut r)? as usize;
+                    if i >= DIST_BASE.len() {
+                        return Err(corrupt("bad distance code"));
+                    }
+                    let distance = DIST_BASE[i] as usize + r.bits(DIST_EXTRA[i] as u32)? as usize;
+                    if distance > out.len() {
+                        return Err(corrupt("distance too far back"));
+                    }
+                    // The match can overlap what it's copying, so it goes a byte at a time.
+                    let start = out.len() - distance;
+                    for k in 0 .. len {
+                        out.push(out[start + k]);
+                    }
+                }
+                _ => return Err(corrupt("bad length code")),
+            }
+        }
+        if last {
+            break;
+        }
+    }
+
+    let trailer = data.get(r.pos .. r.pos + 8).ok_or_else(|| corrupt("unexpected end"))?;
+    if crc32(&out).to_le_bytes() != trailer[.. 4] {
+        return Err(corrupt("CRC mismatch"));
+    }
+    if (out.len() as u32).to_le_bytes() != trailer[4 ..] {
+        return Err(corrupt("length mismatch"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let html = "<p>Hello, <b>world</b>!</p>\n".repeat(500) + "<p>Ünïcödé 会議 🚀</p>";
+        for data in [&b""[..], b"a", b"aaaaaaaaaaaaaaaaaaaa", html.as_bytes()] {
+            assert_eq!(decompress(&compress(data)).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn decompresses_what_zlib_writes() {
+        // Python's gzip.compress of the text below, which uses dynamic codes.
+        let dynamic = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xed\xcc\x51\x09\x00\x51\x08\x04\
+            \xc0\x2a\x56\x13\x14\x14\xc4\x85\x55\xfb\x5f\x86\xfb\x7f\x13\x60\x7c\x93\x38\x2b\x4f\
+            \x6b\x5d\x48\xf5\xde\xa9\xdc\x24\x32\x7c\xb0\x8b\x58\x4a\x0b\x95\x0d\x34\x51\x31\x51\
+            \xa8\xe9\x9e\x4a\x37\x9a\xf7\x71\x86\x2e\x7b\x75\x3a\x6b\x64\x69\xc0\xfd\xd5\xff\xeb\
+            \x0f\x81\xe4\x60\x80\x90\x01\x00\x00";
+        let text = (0 .. 400).map(|i| b"etaoin shrdlu"[(i * i + i / 7) % 13]).collect::<Vec<_>>();
+        assert_eq!(decompress(dynamic).unwrap(), text);
+
+        // And with compresslevel=0, which uses a stored block.
+        let stored = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x04\x03\x01\x06\x00\xf9\xff\x73\x74\x6f\
+            \x72\x65\x64\x0b\xf9\x43\x56\x06\x00\x00\x00";
+        assert_eq!(decompress(stored).unwrap(), b"stored");
+    }
+
+    #[test]
+    fn rejects_corrupt_data() {
+        let mut data = compress(b"hello hello hello");
+        assert!(decompress(&data[.. data.len() - 1]).is_err());
+        let last = data.len() - 5;
+        data[last] ^= 1;
+        assert!(decompress(&data).is_err());
+        assert!(decompress(b"<html>not gzip at all</html>").is_err());
+    }
+}
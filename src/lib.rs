@@ -0,0 +1,4782 @@
+use anyhow::{anyhow, Context, Result};
+use dropbox_sdk::auth::{AccessError, PaperAccessError};
+use dropbox_sdk::client_trait::HttpRequestResult;
+use dropbox_sdk::default_client::UserAuthDefaultClient;
+use dropbox_sdk::paper::{ExportFormat, ListPaperDocsArgs, ListPaperDocsFilterBy,
+    ListPaperDocsResponse, ListPaperDocsSortBy, ListPaperDocsSortOrder, PaperDocExportResult};
+use log::{error, info, warn};
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::ToSocketAddrs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+use url::Url;
+
+mod auth;
+mod epub;
+mod gzip;
+mod logger;
+mod minify;
+mod source;
+mod text;
+mod throttle;
+mod upload;
+mod zip;
+
+pub use source::PaperSource;
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct DocInfo {
+    /// The Paper doc ID. Lists from older versions don't have it; see [`DocInfo::fill_id`].
+    #[serde(default)]
+    id: String,
+    url: String,
+    name: String,
+    owner: String,
+    path: String,
+    /// The Paper folders containing the doc, from the root, separated by " / ". Empty if it's
+    /// not in a folder.
+    #[serde(default)]
+    folder: String,
+    /// The revision that was downloaded, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    revision: Option<i64>,
+    /// SHA-256 of the doc as it was exported, before any changes, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    content_hash: Option<String>,
+}
+
+impl DocInfo {
+    /// Work out the ID from the URL, if it isn't known yet.
+    fn fill_id(&mut self) {
+        if self.id.is_empty() {
+            if let Some((id, _)) = paper_doc_link(&self.url) {
+                self.id = id.to_owned();
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct DocList {
+    docs: Vec<DocInfo>,
+    /// Identifies the state of the account as of the last complete `--only-if-changed` run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    validator: Option<String>,
+}
+
+/// Matches image tags, capturing the whole `src` attribute as `attr` and the image URL as `url`,
+/// `url_sq` or `url_bare`, depending on how it's quoted. Use [`captured_url`] to get at it.
+const IMG_RE: &str = r#"<img\b[^>]*?\s(?P<attr>src\s*=\s*(?:"(?P<url>[^"]+)"|'(?P<url_sq>[^']+)'|(?P<url_bare>[^\s"'>]+)))[^>]*>"#;
+
+/// Matches image tags with a `srcset`, capturing the whole attribute as `attr` and its value as
+/// `url` or `url_sq`.
+const SRCSET_RE: &str = r#"<img\b[^>]*?\s(?P<attr>srcset\s*=\s*(?:"(?P<url>[^"]+)"|'(?P<url_sq>[^']+)'))[^>]*>"#;
+
+/// Matches tags with a `style` attribute, capturing the tag name as `name` and the attribute's
+/// value as `style` or `style_sq`, depending on how it's quoted.
+const STYLE_RE: &str = r#"<(?P<name>[a-zA-Z][a-zA-Z0-9]*)\b[^>]*?\sstyle\s*=\s*(?:"(?P<style>[^"]*)"|'(?P<style_sq>[^']*)')[^>]*>"#;
+
+/// Matches `url(...)` in CSS, capturing the URL as `url`, however it's quoted (`&quot;` included,
+/// since that's how double quotes look inside a double-quoted attribute).
+const CSS_URL_RE: &str = r#"url\(\s*(?:"(?P<url_dq>[^"]*)"|'(?P<url_sq>[^']*)'|&quot;(?P<url_ent>.*?)&quot;|(?P<url>[^)"'\s]+))\s*\)"#;
+
+/// Matches `<h1>` to `<h3>` elements, capturing the level as `level`, the attributes as `attrs`,
+/// and the contents as `text`.
+const HEADING_RE: &str = r#"(?is)<h(?P<level>[1-3])\b(?P<attrs>[^>]*)>(?P<text>.*?)</h[1-3]\s*>"#;
+
+/// The URL captured by [`IMG_RE`], [`SRCSET_RE`] or [`LINK_RE`].
+fn captured_url<'h>(c: &regex::bytes::Captures<'h>) -> &'h [u8] {
+    c.name("url")
+        .or_else(|| c.name("url_sq"))
+        .or_else(|| c.name("url_bare"))
+        .expect("no URL captured")
+        .as_bytes()
+}
+
+/// Styling for the HTML docs with `--embed-css`: Paper's HTML has none of its own.
+const EMBEDDED_CSS: &str = "<style>\
+body { font-family: -apple-system, \"Segoe UI\", Helvetica, Arial, sans-serif; \
+line-height: 1.5; color: #222; max-width: 50em; margin: 2em auto; padding: 0 1em; } \
+img { max-width: 100%; height: auto; } \
+pre, code { font-family: Menlo, Consolas, monospace; font-size: 0.9em; } \
+pre { overflow-x: auto; background: #f6f6f6; padding: 0.5em; } \
+table { border-collapse: collapse; } \
+td, th { border: 1px solid #ccc; padding: 0.25em 0.5em; }\
+</style>";
+
+/// Matches links, capturing the target as `url`.
+const LINK_RE: &str = r#"<a( [^>]+)* href="(?P<url>[^"]+)"[^>]*>"#;
+
+/// The result of fetching an image, shared by everyone who wants it.
+type ImageOnce = Arc<std::sync::OnceLock<Result<String, String>>>;
+
+/// Options controlling the run, and how each doc gets fetched and written out.
+struct Config {
+    export: bool,
+    prefix: String,
+    /// How many docs to work on at once.
+    page_threads: usize,
+    retry_budget: RetryBudget,
+    /// How many times to retry each doc or image.
+    max_retries: u32,
+    /// Docs that ran out of retries or couldn't be written, to be tried again at the end.
+    gave_up: Mutex<Vec<String>>,
+    /// Set during that second try, which backs off for longer between retries.
+    final_pass: AtomicBool,
+    /// Line endings to write text output with. `None` means use the default for the format.
+    newlines: Option<Newlines>,
+    abort_on_auth_error: bool,
+    /// Set when the run is being stopped early; workers should skip any docs not yet started.
+    aborting: AtomicBool,
+    /// HTTP agent for image downloads, shared so connections get reused.
+    agent: ureq::Agent,
+    connection_metrics: Option<Arc<ConnectionMetrics>>,
+    /// Compiled [`IMG_RE`].
+    img_re: Regex,
+    /// Compiled [`SRCSET_RE`].
+    srcset_re: Regex,
+    /// Compiled [`STYLE_RE`] and [`CSS_URL_RE`].
+    style_re: Regex,
+    css_url_re: Regex,
+    /// Compiled [`HEADING_RE`].
+    heading_re: Regex,
+    format: OutputFormat,
+    /// If set, every retry gets recorded here.
+    retry_log: Option<Mutex<File>>,
+    /// Where to record every doc and image that failed.
+    error_log: Option<Mutex<File>>,
+    /// How much has been written to the output directory this run, for the summary at the end.
+    bytes_written: AtomicU64,
+    /// Shared directory of previously-downloaded images, keyed by URL hash.
+    image_cache: Option<PathBuf>,
+    /// Whether workers are currently waiting for `.pause` in the output directory to go away.
+    paused: AtomicBool,
+    /// If set, images are named by their content rather than URL, so each is only stored once.
+    /// This remembers the outcome for each URL seen so far.
+    dedupe_content: Option<Mutex<HashMap<String, ImageOnce>>>,
+    /// Images up to this many bytes get put right in the doc instead of in separate files.
+    inline_images: Option<u64>,
+    /// Images bigger than this aren't downloaded at all.
+    max_image_bytes: Option<u64>,
+    /// Limits how fast image requests are made.
+    image_rate: Option<throttle::RateLimiter>,
+    /// Put docs in subfolders matching their Paper folders.
+    nest_folders: bool,
+    /// Download docs again even if they haven't changed.
+    force_refresh: bool,
+    /// Overwrite existing files, images included, instead of keeping them.
+    force: bool,
+    /// Images that have been downloaded again this run, with `force`.
+    refreshed_images: Mutex<HashSet<String>>,
+    /// Leave the download date out of the files, so they only change when the doc does.
+    date_stamp: bool,
+    /// Give times in UTC rather than local time.
+    utc: bool,
+    strip_comments: bool,
+    /// Write HTML docs gzipped, as `.html.gz`.
+    gzip: bool,
+    /// Take the comments and extra whitespace out of HTML docs.
+    minify: bool,
+    /// Also save each doc exactly as Dropbox exported it, next to the processed one.
+    keep_raw: bool,
+    /// Save images embedded in docs as `data:` URIs to files.
+    extract_data_uris: bool,
+    /// Put [`EMBEDDED_CSS`] in each HTML doc's `<head>`.
+    embed_css: bool,
+    /// Put a table of contents at the top of each HTML doc, linking to its headings.
+    toc: bool,
+    /// Where everything gets written.
+    out_dir: PathBuf,
+    /// Only docs whose titles match this are written.
+    title_filter: Option<regex::Regex>,
+    /// Only docs owned by this user are written. Lowercase, to match owners ignoring case.
+    owner_filter: Option<String>,
+    /// Compiled [`LINK_RE`].
+    link_re: Regex,
+
+    // The rest are about the run as a whole rather than any one doc.
+    command: Command,
+    list_args: ListPaperDocsArgs,
+    /// Only the user's own docs are listed, so missing ones haven't necessarily been deleted.
+    only_owned: bool,
+    /// How many old copies of list.json to keep.
+    list_backups: usize,
+    probe_api: bool,
+    /// Work up to `page_threads` docs at once over this long, rather than starting with them all.
+    slow_start: Option<Duration>,
+    only_if_changed: bool,
+    zip_folders: bool,
+    /// Where to write a zip of the whole output directory.
+    zip_to: Option<PathBuf>,
+    /// Delete the files of docs that are gone from Paper.
+    prune: bool,
+    stats_json: bool,
+    sort_by: SortBy,
+    dry_run: bool,
+    image_threads: usize,
+    max_docs: usize,
+    /// Just this doc, instead of listing them all.
+    doc_id: Option<String>,
+    auth_file: Option<PathBuf>,
+    /// Authorize again instead of using saved credentials.
+    reauth: bool,
+    /// Team member to act as, by email.
+    as_member: Option<String>,
+    /// Limits how fast Paper API requests are made, in requests per second.
+    max_rps: Option<f64>,
+    /// Where to write just the docs' metadata, instead of downloading them.
+    metadata_only: Option<PathBuf>,
+    list_only: bool,
+    /// How many images from each doc to check, instead of downloading the docs.
+    test_images: Option<usize>,
+    upload_to: Option<upload::Destination>,
+    upload_logs: bool,
+    check_links: bool,
+    /// Fail if any broken links are found.
+    strict: bool,
+    /// Log one line per doc.
+    compact: bool,
+    quiet: bool,
+    /// Move docs with broken links into a folder of their own.
+    quarantine: bool,
+}
+
+impl Config {
+    /// The defaults, before any command line options are applied.
+    fn new() -> Result<Self> {
+        Ok(Config {
+            export: true,
+            prefix: String::new(),
+            page_threads: 10,
+            retry_budget: RetryBudget { remaining: None },
+            max_retries: 2,
+            gave_up: Mutex::new(vec![]),
+            final_pass: AtomicBool::new(false),
+            newlines: None,
+            abort_on_auth_error: false,
+            aborting: AtomicBool::new(false),
+            agent: ureq::agent(),
+            connection_metrics: None,
+            img_re: Regex::new(IMG_RE).context("invalid image tag regex")?,
+            srcset_re: Regex::new(SRCSET_RE).context("invalid srcset regex")?,
+            style_re: Regex::new(STYLE_RE).context("invalid style regex")?,
+            css_url_re: Regex::new(CSS_URL_RE).context("invalid CSS url regex")?,
+            heading_re: Regex::new(HEADING_RE).context("invalid heading regex")?,
+            format: OutputFormat::Html,
+            retry_log: None,
+            error_log: None,
+            bytes_written: AtomicU64::new(0),
+            image_cache: None,
+            paused: AtomicBool::new(false),
+            date_stamp: true,
+            utc: false,
+            force_refresh: false,
+            force: false,
+            refreshed_images: Mutex::new(HashSet::new()),
+            nest_folders: false,
+            dedupe_content: None,
+            inline_images: None,
+            max_image_bytes: None,
+            image_rate: None,
+            strip_comments: false,
+            embed_css: false,
+            toc: false,
+            extract_data_uris: false,
+            keep_raw: false,
+            gzip: false,
+            minify: false,
+            out_dir: PathBuf::from("docs"),
+            title_filter: None,
+            owner_filter: None,
+            link_re: Regex::new(LINK_RE).context("invalid link regex")?,
+            command: Command::Dump,
+            list_args: ListPaperDocsArgs::default(),
+            only_owned: false,
+            list_backups: 1,
+            probe_api: false,
+            slow_start: None,
+            only_if_changed: false,
+            zip_folders: false,
+            zip_to: None,
+            prune: false,
+            stats_json: false,
+            sort_by: SortBy::Name,
+            dry_run: false,
+            image_threads: 10,
+            max_docs: usize::MAX,
+            doc_id: None,
+            auth_file: None,
+            reauth: false,
+            as_member: None,
+            max_rps: None,
+            metadata_only: None,
+            list_only: false,
+            test_images: None,
+            upload_to: None,
+            upload_logs: false,
+            check_links: false,
+            strict: false,
+            compact: false,
+            quiet: false,
+            quarantine: false,
+        })
+    }
+
+    /// The options given on the command line. Bad ones print the usage and exit.
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut config = Config::new()?;
+        let mut retry_log = false;
+        let mut image_timeout = 30;
+        let mut proxy = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "reauth" => { config.command = Command::Reauth; }
+                "rebuild-index" => { config.command = Command::RebuildIndex; }
+                "stats" => { config.command = Command::Stats; }
+                "--json" => { config.stats_json = true; }
+                "--auth-file" => {
+                    config.auth_file = Some(PathBuf::from(args.next().unwrap_or_else(|| usage())));
+                }
+                "--reauth" => { config.reauth = true; }
+                "--as-member" => {
+                    config.as_member = Some(args.next().unwrap_or_else(|| usage()));
+                }
+                "--no-export" => { config.export = false; }
+                "--prefix" => {
+                    config.prefix = args.next().unwrap_or_else(|| usage());
+                }
+                "--max-retries" => {
+                    config.max_retries = args.next()
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or_else(|| usage());
+                }
+                "--doc-id" => {
+                    config.doc_id = Some(args.next().unwrap_or_else(|| usage()));
+                }
+                "--max-docs" => {
+                    config.max_docs = args.next()
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or_else(|| usage());
+                }
+                "--proxy" => {
+                    proxy = Some(args.next().unwrap_or_else(|| usage()));
+                }
+                "--image-timeout" => {
+                    image_timeout = args.next()
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or_else(|| usage());
+                }
+                "--max-image-bytes" => {
+                    config.max_image_bytes = Some(args.next()
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or_else(|| usage()));
+                }
+                "--total-retry-budget" => {
+                    let n = args.next()
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or_else(|| usage());
+                    config.retry_budget.remaining = Some(AtomicUsize::new(n));
+                }
+                "--image-cache" => {
+                    let dir = PathBuf::from(args.next().unwrap_or_else(|| usage()));
+                    fs::create_dir_all(&dir).with_context(|| {
+                        format!("failed to create image cache {}", dir.display())
+                    })?;
+                    config.image_cache = Some(dir);
+                }
+                "--strip-html-comments" => { config.strip_comments = true; }
+                "--embed-css" => { config.embed_css = true; }
+                "--toc" => { config.toc = true; }
+                "--extract-data-uris" => { config.extract_data_uris = true; }
+                "--keep-raw" => { config.keep_raw = true; }
+                "--gzip" => { config.gzip = true; }
+                "--minify" => { config.minify = true; }
+                "--inline-images" => { config.inline_images = Some(64 * 1024); }
+                s if s.starts_with("--inline-images=") => {
+                    config.inline_images = Some(s["--inline-images=".len() ..].parse()
+                        .unwrap_or_else(|_| usage()));
+                }
+                "--dedupe-content" => { config.dedupe_content = Some(Mutex::new(HashMap::new())); }
+                "--nest-folders" => { config.nest_folders = true; }
+                "--force-refresh" => { config.force_refresh = true; }
+                "--force" => {
+                    config.force_refresh = true;
+                    config.force = true;
+                }
+                "--no-date-stamp" => { config.date_stamp = false; }
+                "--utc" => { config.utc = true; }
+                "--retry-log" => { retry_log = true; }
+                "--filter" => {
+                    let re = args.next().unwrap_or_else(|| usage());
+                    config.title_filter = Some(regex::Regex::new(&re)
+                        .with_context(|| format!("invalid --filter regex {:?}", re))?);
+                }
+                "--owner" => {
+                    config.owner_filter = Some(args.next().unwrap_or_else(|| usage())
+                        .to_lowercase());
+                }
+                "--output-dir" => {
+                    config.out_dir = PathBuf::from(args.next().unwrap_or_else(|| usage()));
+                }
+                "--format" => {
+                    config.format = args.next()
+                        .and_then(|s| OutputFormat::parse(&s))
+                        .unwrap_or_else(|| usage());
+                }
+                "--newlines" => {
+                    config.newlines = Some(args.next()
+                        .and_then(|s| Newlines::parse(&s))
+                        .unwrap_or_else(|| usage()));
+                }
+                "--abort-on-auth-error" => { config.abort_on_auth_error = true; }
+                "--upload-logs" => { config.upload_logs = true; }
+                "--upload" => {
+                    let dest = args.next().unwrap_or_else(|| usage());
+                    match upload::Destination::parse(&dest) {
+                        Ok(dest) => config.upload_to = Some(dest),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                "--connection-metrics" => {
+                    config.connection_metrics = Some(Arc::new(ConnectionMetrics::default()));
+                }
+                "--compact" => { config.compact = true; }
+                "--quiet" => { config.quiet = true; }
+                "--check-links" => { config.check_links = true; }
+                "--strict" => { config.strict = true; }
+                "--quarantine" => { config.quarantine = true; }
+                "--zip-folders" => { config.zip_folders = true; }
+                "--prune" => { config.prune = true; }
+                "--zip" => {
+                    config.zip_to = Some(PathBuf::from(args.next().unwrap_or_else(|| usage())));
+                }
+                "--only-if-changed" => { config.only_if_changed = true; }
+                "--slow-start" => {
+                    let secs = args.next()
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or_else(|| usage());
+                    config.slow_start = Some(Duration::from_secs(secs));
+                }
+                "--probe-api-version" => { config.probe_api = true; }
+                "--page-threads" | "--image-threads" => {
+                    let n = args.next()
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .unwrap_or_else(|| usage());
+                    if n == 0 {
+                        eprintln!("{} must be at least 1", arg);
+                        usage();
+                    }
+                    if arg == "--page-threads" {
+                        config.page_threads = n;
+                    } else {
+                        config.image_threads = n;
+                    }
+                }
+                "--max-rps" | "--max-image-rps" => {
+                    let n = args.next()
+                        .and_then(|n| n.parse::<f64>().ok())
+                        .filter(|n| *n > 0. && n.is_finite())
+                        .unwrap_or_else(|| usage());
+                    if arg == "--max-rps" {
+                        config.max_rps = Some(n);
+                    } else {
+                        config.image_rate = Some(throttle::RateLimiter::new(n));
+                    }
+                }
+                "--dry-run" => { config.dry_run = true; }
+                "--sort" => {
+                    config.sort_by = SortBy::parse(&args.next().unwrap_or_else(|| usage()))
+                        .map_err(|e| anyhow!(e))?;
+                }
+                "--no-backup" => { config.list_backups = 0; }
+                "--list-backups" => {
+                    config.list_backups = args.next()
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or_else(|| usage());
+                }
+                "--list-page-size" => {
+                    let n = args.next()
+                        .and_then(|n| n.parse::<i32>().ok())
+                        .unwrap_or_else(|| usage());
+                    if !(1 ..= 1000).contains(&n) {
+                        eprintln!("--list-page-size must be between 1 and 1000");
+                        std::process::exit(1);
+                    }
+                    config.list_args = config.list_args.with_limit(n);
+                }
+                "--filter-by" => {
+                    let filter = match args.next().as_deref() {
+                        Some("owned") => ListPaperDocsFilterBy::DocsCreated,
+                        Some("accessed") => ListPaperDocsFilterBy::DocsAccessed,
+                        _ => usage(),
+                    };
+                    config.only_owned = filter == ListPaperDocsFilterBy::DocsCreated;
+                    config.list_args = config.list_args.with_filter_by(filter);
+                }
+                "--list-sort" => {
+                    let sort = match args.next().as_deref() {
+                        Some("modified") => ListPaperDocsSortBy::Modified,
+                        Some("created") => ListPaperDocsSortBy::Created,
+                        Some("accessed") => ListPaperDocsSortBy::Accessed,
+                        _ => usage(),
+                    };
+                    // Newest first, so that --max-docs gets the most recent ones.
+                    config.list_args = config.list_args.with_sort_by(sort)
+                        .with_sort_order(ListPaperDocsSortOrder::Descending);
+                }
+                "--test-image-urls" => {
+                    config.test_images = Some(args.next()
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .unwrap_or_else(|| usage()));
+                }
+                "--metadata-only" => {
+                    let path = args.next().unwrap_or_else(|| usage());
+                    config.metadata_only = Some(PathBuf::from(path));
+                }
+                "--list-only" => { config.list_only = true; }
+                _ => usage(),
+            }
+        }
+
+        let mut agent = ureq::AgentBuilder::new()
+            .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+            .timeout_connect(Duration::from_secs(image_timeout))
+            .timeout_read(Duration::from_secs(image_timeout));
+        // The Dropbox SDK makes its own connections, with no way to give it a proxy, so this only
+        // covers images.
+        let proxy = proxy.or_else(|| {
+            ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"].iter()
+                .filter_map(|name| env::var(name).ok())
+                .find(|v| !v.is_empty())
+        });
+        if let Some(proxy) = proxy {
+            agent = agent.proxy(ureq::Proxy::new(&proxy)
+                .with_context(|| format!("invalid proxy {:?}", proxy))?);
+        }
+        if let Some(metrics) = &config.connection_metrics {
+            let metrics = Arc::clone(metrics);
+            agent = agent.resolver(move |netloc: &str| {
+                metrics.new_connection(netloc);
+                netloc.to_socket_addrs().map(|addrs| addrs.collect())
+            });
+        }
+        config.agent = agent.build();
+
+        if retry_log {
+            let path = config.out_dir.join("retries.log");
+            fs::create_dir_all(&config.out_dir)
+                .with_context(|| format!("failed to create {}", config.out_dir.display()))?;
+            let file = OpenOptions::new().create(true).append(true).open(&path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            config.retry_log = Some(Mutex::new(file));
+        }
+
+        if config.gzip && config.format != OutputFormat::Html {
+            eprintln!("--gzip only works with --format html");
+            usage();
+        }
+
+        if config.dumping() {
+            // Find out now if anything can't be written, rather than after listing every doc.
+            check_writable(&config.out_dir)?;
+            if config.export {
+                check_writable(&config.out_dir.join("images"))?;
+            }
+            let path = config.out_dir.join("errors.log");
+            let file = File::create(&path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            config.error_log = Some(Mutex::new(file));
+        }
+
+        config.auth_file = config.auth_file.take().or_else(auth::default_path);
+        Ok(config)
+    }
+
+    /// Whether docs are going to be downloaded, rather than just listed or looked at.
+    fn dumping(&self) -> bool {
+        matches!(self.command, Command::Dump) && self.metadata_only.is_none() && !self.list_only
+            && self.test_images.is_none() && !self.dry_run
+    }
+
+    /// The line endings to write files of the given format with.
+    fn newlines_for(&self, format: OutputFormat) -> Newlines {
+        self.newlines.unwrap_or_else(|| format.default_newlines())
+    }
+
+    /// The current time in RFC 3339 form: local time, or UTC with `--utc`.
+    fn now_rfc3339(&self) -> String {
+        if self.utc {
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        } else {
+            chrono::Local::now().to_rfc3339()
+        }
+    }
+
+    /// The current time for file timestamps, which don't say what time zone they're in.
+    fn now_naive(&self) -> chrono::NaiveDateTime {
+        if self.utc {
+            chrono::Utc::now().naive_utc()
+        } else {
+            chrono::Local::now().naive_local()
+        }
+    }
+
+    /// Note a retry in the retry log, if there is one. `what` is "doc" or "image".
+    fn log_retry(&self, what: &str, id: &str, attempt: u32, category: &str) {
+        if let Some(log) = &self.retry_log {
+            let mut file = log.lock().unwrap();
+            let _ = writeln!(file, "{}\t{}\t{}\tattempt {}\t{}",
+                self.now_rfc3339(), what, id, attempt, category);
+            let _ = file.flush();
+        }
+    }
+
+    /// Count a file that was just written towards the run's total.
+    fn note_written(&self, path: &Path) {
+        if let Ok(meta) = fs::metadata(path) {
+            self.bytes_written.fetch_add(meta.len(), Ordering::SeqCst);
+        }
+    }
+
+    /// Note a failure in the error log, if there is one. `what` is "doc" or "image"; either way,
+    /// `id` is the doc's ID, so that it can be matched up with list.json and the retry log.
+    fn log_error(&self, what: &str, id: &str, msg: &str) {
+        if let Some(log) = &self.error_log {
+            let mut file = log.lock().unwrap();
+            let _ = writeln!(file, "{}\t{}\t{}\t{}",
+                self.now_rfc3339(), what, id, msg.trim());
+            let _ = file.flush();
+        }
+    }
+}
+
+/// For `--dry-run`: get each doc's metadata and say whether it would be downloaded, without
+/// writing anything. `known` has the revisions of docs already downloaded.
+fn dry_run_report(
+    ids: Vec<String>,
+    client: Arc<dyn PaperSource>,
+    config: Arc<Config>,
+    known: HashMap<String, Option<i64>>,
+) -> Result<()> {
+    let pool = ThreadPool::new(config.page_threads);
+    let known = Arc::new(known);
+    // new, already downloaded, changed since downloaded, errors
+    let counts = Arc::new(Mutex::new((0, 0, 0, 0)));
+    for id in ids.into_iter() {
+        let client = Arc::clone(&client);
+        let config = Arc::clone(&config);
+        let known = Arc::clone(&known);
+        let counts = Arc::clone(&counts);
+        pool.execute(move || {
+            let url = format!("https://paper.dropbox.com/doc/{}", id);
+            let mut output = url.clone() + "\n";
+            if config.aborting.load(Ordering::SeqCst) {
+                return;
+            }
+            let result = download_doc(&id, &*client, &config, false, ExportFormat::Html, &mut output);
+            let failed = result.is_none();
+            {
+                let mut counts = counts.lock().unwrap();
+                match (result, known.get(&url)) {
+                    (None, _) => counts.3 += 1,
+                    (Some(result), None) => {
+                        output += &format!("new: {}\n", result.result.title);
+                        counts.0 += 1;
+                    }
+                    (Some(result), Some(revision)) => {
+                        counts.1 += 1;
+                        if *revision == Some(result.result.revision) {
+                            output += &format!("already downloaded: {}\n", result.result.title);
+                        } else {
+                            output += &format!("changed: {}\n", result.result.title);
+                            counts.2 += 1;
+                        }
+                    }
+                }
+            }
+            if failed {
+                warn!("{}", output.trim_end());
+            } else {
+                info!("{}", output.trim_end());
+            }
+        });
+    }
+    pool.join();
+
+    if config.aborting.load(Ordering::SeqCst) {
+        return Err(anyhow!(AUTH_FAILED));
+    }
+
+    let (new, existing, changed, errors) = *counts.lock().unwrap();
+    println!("{} new, {} already downloaded ({} changed since), {} errors",
+        new, existing, changed, errors);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Html,
+    /// An EPUB book per doc, with its images embedded.
+    Epub,
+    /// Paper's Markdown export, as-is; images are left pointing at their original URLs.
+    Markdown,
+    /// HTML as usual, plus the Markdown alongside it.
+    Both,
+    /// Just the text, with no markup or images.
+    Text,
+}
+
+impl OutputFormat {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "html" => Some(OutputFormat::Html),
+            "epub" => Some(OutputFormat::Epub),
+            "markdown" => Some(OutputFormat::Markdown),
+            "both" => Some(OutputFormat::Both),
+            "text" => Some(OutputFormat::Text),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Html | OutputFormat::Both => "html",
+            OutputFormat::Epub => "epub",
+            OutputFormat::Markdown => "md",
+            OutputFormat::Text => "txt",
+        }
+    }
+
+    /// The line endings to write this format with when `--newlines` isn't given.
+    fn default_newlines(self) -> Newlines {
+        match self {
+            OutputFormat::Markdown => Newlines::Lf,
+            _ => Newlines::Preserve,
+        }
+    }
+}
+
+/// Counts of requests and newly opened connections per host, to see how well connection reuse is
+/// working.
+#[derive(Default)]
+struct ConnectionMetrics {
+    /// Keyed by "host:port"; values are (requests, new connections).
+    hosts: Mutex<HashMap<String, (usize, usize)>>,
+}
+
+impl ConnectionMetrics {
+    fn request(&self, url: &Url) {
+        if let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) {
+            self.hosts.lock().unwrap()
+                .entry(format!("{}:{}", host, port))
+                .or_default()
+                .0 += 1;
+        }
+    }
+
+    /// The agent only resolves a host when it needs to open a new connection, so this gets
+    /// called from the resolver.
+    fn new_connection(&self, netloc: &str) {
+        self.hosts.lock().unwrap()
+            .entry(netloc.to_owned())
+            .or_default()
+            .1 += 1;
+    }
+
+    fn print(&self) {
+        let hosts = self.hosts.lock().unwrap();
+        let mut hosts = hosts.iter().collect::<Vec<_>>();
+        hosts.sort();
+        info!("connection metrics:");
+        for (host, (requests, new)) in hosts {
+            info!("    {}: {} requests, {} new connections, {} reused",
+                host, requests, new, requests.saturating_sub(*new));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Newlines {
+    Lf,
+    CrLf,
+    /// Leave the bytes as they came from Dropbox.
+    Preserve,
+}
+
+impl Newlines {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "lf" => Some(Newlines::Lf),
+            "crlf" => Some(Newlines::CrLf),
+            "preserve" => Some(Newlines::Preserve),
+            _ => None,
+        }
+    }
+
+    /// Convert all line endings in the given text to this style.
+    fn apply(self, text: Vec<u8>) -> Vec<u8> {
+        if self == Newlines::Preserve {
+            return text;
+        }
+        let mut out = NewlineWriter::new(Vec::with_capacity(text.len()), self);
+        out.write_all(&text).unwrap();
+        out.finish().unwrap()
+    }
+}
+
+/// Converts line endings to a given style on the way through to another writer.
+struct NewlineWriter<W: Write> {
+    inner: W,
+    style: Newlines,
+    /// Whether the last thing written was a CR, so that an LF right after it is part of the same
+    /// line ending.
+    after_cr: bool,
+}
+
+impl<W: Write> NewlineWriter<W> {
+    fn new(inner: W, style: Newlines) -> Self {
+        Self { inner, style, after_cr: false }
+    }
+
+    fn newline(&mut self) -> io::Result<()> {
+        self.inner.write_all(if self.style == Newlines::CrLf { b"\r\n" } else { b"\n" })
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for NewlineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.style == Newlines::Preserve {
+            return self.inner.write(buf);
+        }
+        let mut start = 0;
+        for (i, &b) in buf.iter().enumerate() {
+            if b != b'\r' && b != b'\n' {
+                continue;
+            }
+            if i > start {
+                self.after_cr = false;
+            }
+            self.inner.write_all(&buf[start .. i])?;
+            start = i + 1;
+            if b == b'\n' && self.after_cr {
+                // Second half of a CRLF, already written.
+                self.after_cr = false;
+                continue;
+            }
+            self.after_cr = b == b'\r';
+            self.newline()?;
+        }
+        self.inner.write_all(&buf[start ..])?;
+        if start < buf.len() {
+            self.after_cr = false;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Passes reads through to another reader, but fails once more than a given number of bytes have
+/// come through.
+struct SizeLimitedReader<R: Read> {
+    inner: R,
+    remaining: u64,
+    /// Whether reading failed because of the limit, rather than the inner reader.
+    exceeded: bool,
+}
+
+impl<R: Read> SizeLimitedReader<R> {
+    fn new(inner: R, limit: u64) -> Self {
+        Self { inner, remaining: limit, exceeded: false }
+    }
+}
+
+impl<R: Read> Read for SizeLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::other("size limit exceeded"));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// A limit on the total number of retries made over the whole run, shared between docs and
+/// images, so that a broad outage doesn't have every worker retrying long after it's clear the
+/// server is down.
+struct RetryBudget {
+    remaining: Option<AtomicUsize>,
+}
+
+impl RetryBudget {
+    /// Use up one retry. Returns false if the budget has been exhausted and the caller should give
+    /// up instead.
+    fn take(&self) -> bool {
+        match &self.remaining {
+            None => true,
+            Some(n) => n
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok(),
+        }
+    }
+}
+
+/// How long to wait before retry number `attempt` (counting from 1): exponential backoff starting
+/// at a second, up to a minute, with up to half a second of random jitter so that threads which
+/// failed together don't retry together.
+fn backoff(attempt: u32) -> Duration {
+    use ring::rand::{SecureRandom, SystemRandom};
+    let mut jitter = [0u8; 2];
+    let _ = SystemRandom::new().fill(&mut jitter);
+    let jitter_ms = u64::from(u16::from_le_bytes(jitter)) % 500;
+    let base = Duration::from_secs(1 << attempt.saturating_sub(1).min(6)).min(Duration::from_secs(60));
+    base + Duration::from_millis(jitter_ms)
+}
+
+/// How to order the doc list and index.
+#[derive(Debug, Clone, Copy)]
+enum SortBy {
+    Name,
+    Owner,
+}
+
+impl SortBy {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "name" => Ok(SortBy::Name),
+            "owner" => Ok(SortBy::Owner),
+            "date" => Err("can't sort by date: the Paper API doesn't say when docs were modified"
+                .to_owned()),
+            _ => Err(format!("unknown sort order {:?}; use name or owner", s)),
+        }
+    }
+
+    /// Docs with the same name (and owner) are ordered by URL, so the order is the same every
+    /// time.
+    fn sort(self, docs: &mut [DocInfo]) {
+        match self {
+            SortBy::Name => docs.sort_by(|a, b| a.name.cmp(&b.name).then(a.url.cmp(&b.url))),
+            SortBy::Owner => docs.sort_by(|a, b| a.owner.cmp(&b.owner)
+                .then(a.name.cmp(&b.name))
+                .then(a.url.cmp(&b.url))),
+        }
+    }
+}
+
+/// What the program has been asked to do.
+enum Command {
+    /// Download docs; the default.
+    Dump,
+    /// Authorize again and replace the token in the auth file.
+    Reauth,
+    /// Regenerate list.json and index.html from the files already downloaded.
+    RebuildIndex,
+    /// Summarize a finished dump.
+    Stats,
+}
+
+fn usage() -> ! {
+    eprintln!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    eprintln!("usage: legacy-paper-dump [options]");
+    eprintln!("       legacy-paper-dump reauth [--auth-file PATH]");
+    eprintln!("       legacy-paper-dump rebuild-index");
+    eprintln!("       legacy-paper-dump stats [--json]");
+    eprintln!("unless --no-export is specified, writes all docs to the output directory, \
+        <out-dir> below: 'docs' in the current directory, or the one given by --output-dir.");
+    eprintln!();
+    eprintln!("commands:");
+    eprintln!("    reauth               authorize with Dropbox again, replacing the saved token");
+    eprintln!("    rebuild-index        regenerate <out-dir>/list.json and <out-dir>/index.html");
+    eprintln!("                         from the HTML files already in the output directory,");
+    eprintln!("                         without contacting Dropbox");
+    eprintln!("    stats [--json]       summarize the docs and images in the output directory, as");
+    eprintln!("                         a table or JSON");
+    eprintln!();
+    eprintln!("options:");
+    eprintln!("    --auth-file PATH     keep Dropbox credentials in PATH between runs (default:");
+    eprintln!("                         ~/.config/legacy-paper-dump/token)");
+    eprintln!("    --reauth             authorize with Dropbox again before the dump, replacing the");
+    eprintln!("                         saved token");
+    eprintln!("    --as-member EMAIL    with a team token, dump the docs of the team member with");
+    eprintln!("                         that email address");
+    eprintln!("    --no-export          only list the docs; don't write anything out");
+    eprintln!("    --prefix STRING      prepend STRING to every doc's filename");
+    eprintln!("    --max-retries N      retry each doc or image up to N times (default 2)");
+    eprintln!("    --total-retry-budget N");
+    eprintln!("                         give up retrying any doc or image after N retries total");
+    eprintln!("    --image-cache DIR    share downloaded images between dumps through DIR");
+    eprintln!("    --proxy URL          fetch images through the proxy at URL (default: from");
+    eprintln!("                         HTTPS_PROXY or HTTP_PROXY); the Dropbox API doesn't use it");
+    eprintln!("    --max-image-bytes N  skip images bigger than N bytes");
+    eprintln!("    --image-timeout SECS give up on an image connection after SECS seconds of no");
+    eprintln!("                         progress (default 30)");
+    eprintln!("    --strip-html-comments");
+    eprintln!("                         remove HTML comments from the docs");
+    eprintln!("    --embed-css          style the HTML docs for easier reading in a browser");
+    eprintln!("    --toc                put a table of contents linking to the headings at the");
+    eprintln!("                         top of each HTML doc");
+    eprintln!("    --inline-images[=MAXBYTES]");
+    eprintln!("                         put images up to MAXBYTES (default 65536) right in the");
+    eprintln!("                         doc as data: URIs, instead of in separate files");
+    eprintln!("    --keep-raw           also save each doc as exported, before any changes, in a");
+    eprintln!("                         NAME.raw.html (or .raw.md) file next to it");
+    eprintln!("    --extract-data-uris  save images embedded in the docs as data: URIs to files,");
+    eprintln!("                         named by their content");
+    eprintln!("    --dedupe-content     name images by their content, so that the same image at");
+    eprintln!("                         different URLs is only stored once");
+    eprintln!("    --nest-folders       put docs in subfolders matching their Paper folders");
+    eprintln!("    --force-refresh      download every doc again, even if it hasn't changed");
+    eprintln!("    --force              download every doc and image again, overwriting whatever");
+    eprintln!("                         files are already there");
+    eprintln!("    --no-date-stamp      don't put the download date in each file, so re-downloading");
+    eprintln!("                         an unchanged doc gives an identical file");
+    eprintln!("    --utc                give the download date and other times in UTC, instead of");
+    eprintln!("                         local time");
+    eprintln!("    --doc-id ID          only do the doc with the given ID, without listing the");
+    eprintln!("                         rest; the existing list and index keep the other docs");
+    eprintln!("    --max-docs N         only do the first N docs, in the order the API lists them");
+    eprintln!("    --filter REGEX       only write docs whose titles match REGEX");
+    eprintln!("    --owner NAME         only write docs owned by NAME (ignoring case)");
+    eprintln!("    --output-dir PATH    write everything to PATH instead of 'docs'");
+    eprintln!("    --retry-log          record every retry in <out-dir>/retries.log");
+    eprintln!("                         (failures always go in <out-dir>/errors.log)");
+    eprintln!("    --format html|epub|markdown|both|text");
+    eprintln!("                         what to write each doc as (default: html); 'both' writes");
+    eprintln!("                         HTML and Markdown, exporting each doc twice, so it takes");
+    eprintln!("                         twice the API calls; 'text' writes just the text");
+    eprintln!("    --gzip               write HTML docs compressed, as NAME.html.gz (images are left");
+    eprintln!("                         as they are); only for --format html");
+    eprintln!("    --minify             take comments and extra whitespace out of HTML docs");
+    eprintln!("    --newlines lf|crlf|preserve");
+    eprintln!("                         line endings to write docs with (default: lf for");
+    eprintln!("                         Markdown, preserve for everything else)");
+    eprintln!("    --abort-on-auth-error");
+    eprintln!("                         stop the whole run if Dropbox rejects the credentials");
+    eprintln!("    --upload s3://BUCKET/PREFIX | https://WEBDAV/PATH");
+    eprintln!("                         after the dump is done, upload it to S3 or WebDAV");
+    eprintln!("    --upload-logs        upload errors.log and retries.log too");
+    eprintln!("    --connection-metrics print per-host connection reuse stats for image downloads");
+    eprintln!("    --compact            print one line per doc instead of the full log");
+    eprintln!("    --quiet              only print warnings, errors, the docs that had any, and the");
+    eprintln!("                         summary at the end");
+    eprintln!("    --check-links        after the dump, check that all local links and images exist");
+    eprintln!("    --strict             exit with an error if --check-links finds broken links");
+    eprintln!("    --quarantine         check links, and move docs with broken ones to");
+    eprintln!("                         <out-dir>/quarantine");
+    eprintln!("    --zip-folders        also write each top-level Paper folder's docs and images to");
+    eprintln!("                         its own zip file in <out-dir>/zips");
+    eprintln!("    --prune              delete docs that are gone from Paper since the last run");
+    eprintln!("    --zip PATH           after the dump, also pack the whole output folder into a");
+    eprintln!("                         zip file at PATH");
+    eprintln!("    --only-if-changed    exit early if no doc has been added or edited since the last");
+    eprintln!("                         run with this option");
+    eprintln!("    --slow-start SECS    start docs slowly at first, ramping up to full speed over");
+    eprintln!("                         the first SECS seconds");
+    eprintln!("    --probe-api-version  just check that the Paper API is still available, and exit");
+    eprintln!("    --page-threads N     download N docs at a time (default 10)");
+    eprintln!("    --image-threads N    download N images at a time (default 10)");
+    eprintln!("    --max-rps N          make at most N Dropbox API calls a second, across all threads");
+    eprintln!("    --max-image-rps N    start at most N image downloads a second");
+    eprintln!("    --dry-run            list what would be downloaded, without writing anything");
+    eprintln!("    --sort name|owner    how to order the doc list and index (default: name)");
+    eprintln!("    --list-backups N     keep N previous versions of <out-dir>/list.json and");
+    eprintln!("                         <out-dir>/index.html, as FILE.bak, FILE.bak.2...");
+    eprintln!("                         (default 1)");
+    eprintln!("    --no-backup          don't keep previous versions; same as --list-backups 0");
+    eprintln!("    --list-page-size N   list docs N at a time (1-1000, default 1000)");
+    eprintln!("    --filter-by owned|accessed");
+    eprintln!("                         list only the docs you created, or every doc you've");
+    eprintln!("                         accessed (the default)");
+    eprintln!("    --list-sort modified|created|accessed");
+    eprintln!("                         list docs newest first by that time; this is the order");
+    eprintln!("                         they're fetched in (and what --max-docs takes the first");
+    eprintln!("                         of), not the order in the index, which is --sort");
+    eprintln!("    --test-image-urls N  check that images in N docs are fetchable; write nothing");
+    eprintln!("    --metadata-only PATH.json");
+    eprintln!("                         write just the metadata of every doc to a JSON file");
+    eprintln!("    --list-only          print the metadata of every doc to stdout as JSON, in the");
+    eprintln!("                         form used in list.json, and write no files at all");
+    std::process::exit(1);
+}
+
+/// Run the program with the command line arguments it was started with.
+pub fn run() -> Result<()> {
+    logger::init();
+    let config = Config::from_args(env::args().skip(1))?;
+    if config.quiet {
+        log::set_max_level(log::max_level().min(log::LevelFilter::Warn));
+    }
+    let config = Arc::new(config);
+
+    match config.command {
+        Command::Reauth => {
+            let path = config.auth_file.as_ref()
+                .context("reauth needs --auth-file to know where to save the token")?;
+            let saved = auth::authorize_interactively()?;
+            auth::save(path, &saved)?;
+            info!("saved new credentials to {}", path.display());
+            return Ok(());
+        }
+        Command::RebuildIndex => {
+            let mut docs = rebuild_doc_list(&config.out_dir)?;
+            config.sort_by.sort(&mut docs.docs);
+            info!("found {} docs", docs.docs.len());
+            write_list_and_index(&config.out_dir, &docs, config.list_backups, false);
+            return Ok(());
+        }
+        Command::Stats => return print_stats(&config.out_dir, config.stats_json),
+        Command::Dump => (),
+    }
+
+    let client = connect(&config)?;
+
+    if config.probe_api {
+        client.list(&ListPaperDocsArgs::default().with_limit(1))
+            .map_err(explain_list_error)
+            .context("paper/docs/list HTTP or transport err")?
+            .context("paper/docs/list API err")?;
+        info!("the Paper API is available for this account");
+        return Ok(());
+    }
+
+    if !config.dumping() {
+        return report_docs(config, client);
+    }
+
+    let (docs, broken) = match dump(&config, &client)? {
+        Some(dumped) => dumped,
+        None => return Ok(()),
+    };
+
+    if config.zip_folders && config.export {
+        write_folder_zips(&config.out_dir, &docs.docs, &config)?;
+    }
+
+    if let (Some(zip_path), true) = (&config.zip_to, config.export) {
+        write_dump_zip(&config.out_dir, zip_path, &config)?;
+    }
+
+    if config.aborting.load(Ordering::SeqCst) {
+        return Err(anyhow!(AUTH_FAILED));
+    }
+
+    if config.check_links || config.quarantine {
+        report_broken_links(&config, broken)?;
+    }
+
+    if let Some(dest) = &config.upload_to {
+        upload_output(&config, dest)?;
+    }
+
+    Ok(())
+}
+
+/// Log in, with saved credentials if there are any, and set up a client for the Paper API.
+fn connect(config: &Config) -> Result<Arc<dyn PaperSource>> {
+    let saved_auth = match &config.auth_file {
+        Some(path) if !config.reauth => auth::load(path)?,
+        _ => None,
+    };
+    let authorization = match saved_auth.map(|saved| saved.auth)
+        .or_else(|| if config.reauth { None } else { auth::from_env() })
+    {
+        Some(auth) => auth,
+        None => {
+            let saved = auth::authorize_interactively()?;
+            match &config.auth_file {
+                Some(path) => {
+                    auth::save(path, &saved)?;
+                    info!("saved credentials to {}", path.display());
+                }
+                None => warn!("nowhere to save credentials; use --auth-file to keep them"),
+            }
+            saved.auth
+        }
+    };
+    let client: Arc<dyn PaperSource> = match &config.as_member {
+        Some(email) => {
+            let client = source::MemberClient::new(authorization, email)?;
+            info!("acting as team member {}", email);
+            Arc::new(client)
+        }
+        None => Arc::new(UserAuthDefaultClient::new(authorization)),
+    };
+    Ok(match config.max_rps {
+        Some(rate) => Arc::new(source::Throttled {
+            inner: client,
+            limiter: throttle::RateLimiter::new(rate),
+        }),
+        None => client,
+    })
+}
+
+/// The doc list written by the last run, or an empty one if there isn't one that can be read.
+fn load_doc_list(path: &Path) -> DocList {
+    match File::open(path) {
+        Ok(file) => match serde_json::from_reader::<_, DocList>(file) {
+            Ok(mut list) => {
+                list.docs.iter_mut().for_each(DocInfo::fill_id);
+                list
+            }
+            Err(e) => {
+                warn!("error deserializing {}: {}", path.display(), e);
+                DocList::default()
+            }
+        }
+        Err(e) => {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("error opening {}: {}", path.display(), e);
+            }
+            DocList::default()
+        }
+    }
+}
+
+/// Start listing the docs to work on: every one, or just the one given with `--doc-id`.
+fn list_docs<'a>(client: &'a dyn PaperSource, config: &Config) -> Result<DocIds<'a>> {
+    Ok(match &config.doc_id {
+        Some(id) => DocIds::single(client, id.clone()),
+        None => {
+            let result = client.list(&config.list_args)
+                .map_err(explain_list_error)
+                .context("paper/docs/list HTTP or transport err")?
+                .context("paper/docs/list API err")?;
+            DocIds::new(client, result)
+        }
+    })
+}
+
+/// For `--metadata-only`, `--list-only`, `--test-image-urls` and `--dry-run`, which look at the
+/// docs without downloading them.
+fn report_docs(config: Arc<Config>, client: Arc<dyn PaperSource>) -> Result<()> {
+    let ids = {
+        let mut doc_ids = list_docs(&*client, &config)?;
+        let ids = doc_ids.by_ref().take(config.max_docs).collect::<Vec<_>>();
+        if let Some(e) = doc_ids.error {
+            return Err(e);
+        }
+        ids
+    };
+    if let Some(path) = config.metadata_only.clone() {
+        return dump_metadata(ids, client, config, &path);
+    }
+    if config.list_only {
+        return print_doc_list(ids, client, config);
+    }
+    if let Some(sample) = config.test_images {
+        return test_image_urls(ids, client, config, sample);
+    }
+    let list = load_doc_list(&config.out_dir.join("list.json"));
+    let known = list.docs.iter().map(|doc| (doc.url.clone(), doc.revision)).collect();
+    dry_run_report(ids, client, config, known)
+}
+
+/// Download the docs, then write list.json and the index, checking the local links first if
+/// asked to. Returns the docs and any broken links in them, or `None` if `--only-if-changed` found
+/// nothing to do.
+fn dump(config: &Arc<Config>, client: &Arc<dyn PaperSource>)
+    -> Result<Option<(DocList, Vec<BrokenLink>)>>
+{
+    let mut list = load_doc_list(&config.out_dir.join("list.json"));
+
+    let mut validator = None;
+    if config.doc_id.is_some() {
+        // Doing one doc doesn't say anything about the others, so keep what the last full run
+        // found.
+        validator = list.validator.clone();
+    } else if config.only_if_changed {
+        validator = listing_validator(&**client, config, &config.list_args);
+        if validator.is_some() && validator == list.validator {
+            info!("no changes since the last run");
+            return Ok(None);
+        }
+    }
+
+    let single_doc = config.doc_id.is_some();
+    let mut doc_ids = list_docs(&**client, config)?;
+
+    catch_interrupts();
+
+    let mut map = HashMap::new();
+    for doc in list.docs.drain(..) {
+        map.insert(doc.url.clone(), doc);
+    }
+    let map = Arc::new(Mutex::new(map));
+
+    let page_threads = config.page_threads;
+    let pages_pool = ThreadPool::new(page_threads);
+    let images_pool = Arc::new(Mutex::new(ThreadPool::new(config.image_threads)));
+    let failed = Arc::new(Mutex::new(vec![]));
+    let summary = Arc::new(Mutex::new(RunSummary::default()));
+    // Held while writing a checkpoint, so two workers don't write one at the same time. Set once
+    // the list from the last run has been backed up, which happens before the first checkpoint
+    // overwrites it.
+    let checkpoint = Arc::new(Mutex::new(false));
+
+    // Run docs through the workers, feeding them ids through a bounded channel so that only a few
+    // are queued up ahead of them at a time instead of the whole list.
+    let run_pass = |ids: &mut dyn Iterator<Item = String>, total: Arc<AtomicUsize>,
+        slow_start: Option<Duration>|
+    {
+        let done = Arc::new(AtomicUsize::new(0));
+        let (id_tx, id_rx) = mpsc::sync_channel::<String>(page_threads * 2);
+        let id_rx = Arc::new(Mutex::new(id_rx));
+        for _ in 0 .. page_threads {
+            let id_rx = Arc::clone(&id_rx);
+            let client = Arc::clone(client);
+            let images_pool = Arc::clone(&images_pool);
+            let doc_map = Arc::clone(&map);
+            let config = Arc::clone(config);
+            let done = Arc::clone(&done);
+            let failed = Arc::clone(&failed);
+            let checkpoint = Arc::clone(&checkpoint);
+            let total = Arc::clone(&total);
+            let summary = Arc::clone(&summary);
+            let list_path = config.out_dir.join("list.json");
+            pages_pool.execute(move || loop {
+                let id = match id_rx.lock().unwrap().recv() {
+                    Ok(id) => id,
+                    Err(_) => break, // all ids have been handed out
+                };
+                let report = fetch_doc(&id, Arc::clone(&client), Arc::clone(&config),
+                    Arc::clone(&images_pool), Arc::clone(&doc_map));
+                let n = done.fetch_add(1, Ordering::SeqCst) + 1;
+                if n.is_multiple_of(CHECKPOINT_EVERY) {
+                    // Save progress so far, in case the run gets interrupted.
+                    let mut backed_up = checkpoint.lock().unwrap();
+                    if !*backed_up {
+                        if let Err(e) = backup_file(&list_path, config.list_backups) {
+                            warn!("failed to back up {}: {}", list_path.display(), e);
+                        }
+                        *backed_up = true;
+                    }
+                    let mut docs = DocList {
+                        docs: doc_map.lock().unwrap().values().cloned().collect(),
+                        validator: None,
+                    };
+                    config.sort_by.sort(&mut docs.docs);
+                    if let Err(e) = write_doc_list(&list_path, &docs) {
+                        warn!("failed to checkpoint {}: {}", list_path.display(), e);
+                    }
+                }
+                if let DocStatus::Fail(reason) = &report.status {
+                    failed.lock().unwrap().push(id.clone());
+                    let retry_later = !config.final_pass.load(Ordering::SeqCst)
+                        && config.gave_up.lock().unwrap().contains(&id);
+                    if !retry_later {
+                        config.log_error("doc", &id, reason);
+                        summary.lock().unwrap().add(&report);
+                    }
+                } else {
+                    summary.lock().unwrap().add(&report);
+                }
+                let had_errors = matches!(report.status, DocStatus::Fail(_))
+                    || report.images.is_some_and(|(ok, total)| ok < total);
+                let line = if config.compact {
+                    report.compact_line(&id, n, total.load(Ordering::SeqCst).min(config.max_docs))
+                } else {
+                    report.log.trim_end().to_owned()
+                };
+                if had_errors {
+                    warn!("{}", line);
+                } else {
+                    info!("{}", line);
+                }
+            });
+        }
+
+        let started = Instant::now();
+        let mut next_start = started;
+        for id in ids {
+            if let Some(ramp) = slow_start.filter(|ramp| started.elapsed() < *ramp) {
+                // Start at one doc per second, speeding up steadily until the ramp ends.
+                let progress = started.elapsed().as_secs_f64() / ramp.as_secs_f64();
+                let per_sec = 1. + (page_threads - 1) as f64 * progress;
+                let now = Instant::now();
+                if next_start > now {
+                    thread::sleep(next_start - now);
+                }
+                next_start = next_start.max(now) + Duration::from_secs_f64(1. / per_sec);
+            }
+            // This blocks while the queue is full.
+            if interrupted() || id_tx.send(id).is_err() {
+                break;
+            }
+        }
+        drop(id_tx);
+
+        pages_pool.join();
+    };
+
+    // Docs start downloading as soon as the first page of the listing is in, while the rest of
+    // it is fetched as needed.
+    let listed = Arc::clone(&doc_ids.listed);
+    let mut seen = HashSet::new();
+    let mut ids = doc_ids.by_ref().take(config.max_docs).inspect(|id| { seen.insert(id.clone()); });
+    run_pass(&mut ids, listed, config.slow_start);
+    // Only a complete listing says anything about which docs are gone.
+    let listed_everything = !single_doc && !config.only_owned && doc_ids.finished()
+        && !interrupted();
+
+    // Anything that ran out of retries or couldn't be written gets one more go, now that
+    // everything else is done and whatever went wrong may have cleared up.
+    let retry_ids = std::mem::take(&mut *config.gave_up.lock().unwrap());
+    if !retry_ids.is_empty() && !config.aborting.load(Ordering::SeqCst) && !interrupted() {
+        info!("trying {} docs that failed earlier again", retry_ids.len());
+        config.final_pass.store(true, Ordering::SeqCst);
+        let already_failed = {
+            let mut failed = failed.lock().unwrap();
+            failed.retain(|id| !retry_ids.contains(id));
+            failed.len()
+        };
+        let count = retry_ids.len();
+        run_pass(&mut retry_ids.into_iter(), Arc::new(AtomicUsize::new(count)), None);
+        let failed = failed.lock().unwrap();
+        let failed_again = &failed[already_failed ..];
+        info!("recovered {} of {} docs", count - failed_again.len(), count);
+        for id in failed_again {
+            error!("failed again: https://paper.dropbox.com/doc/{}", id);
+        }
+    }
+
+    summary.lock().unwrap().print(config.bytes_written.load(Ordering::SeqCst));
+
+    if let Some(metrics) = &config.connection_metrics {
+        metrics.print();
+    }
+
+    let mut docs = DocList {
+        docs: Arc::try_unwrap(map)
+            .unwrap_or_else(|_| panic!("unable to unwrap doc map arc"))
+            .into_inner()
+            .expect("unable to unwrap doc ma mutex")
+            .into_values()
+            .collect(),
+        // Only if everything was fetched; otherwise the next run needs to try again.
+        validator: validator.filter(|_| failed.lock().unwrap().is_empty()
+            && !config.aborting.load(Ordering::SeqCst) && !interrupted()
+            && doc_ids.error.is_none()),
+    };
+
+    config.sort_by.sort(&mut docs.docs);
+
+    if listed_everything {
+        find_deleted_docs(&config.out_dir, &mut docs.docs, &seen, config.prune)?;
+    }
+
+    if config.export {
+        link_docs_locally(&config.out_dir, &docs.docs, &config.link_re)?;
+    }
+
+    let mut broken = vec![];
+    if config.check_links || config.quarantine {
+        broken = check_local_links(&config.out_dir, &docs.docs, &config.img_re, &config.link_re)?;
+        if config.quarantine {
+            quarantine_docs(&config.out_dir, &mut docs.docs, &broken, config, &config.link_re)?;
+        }
+    }
+
+    // If there was a checkpoint, the old list has been backed up already, and what's there now
+    // is from this run.
+    let list_backed_up = *checkpoint.lock().unwrap();
+    write_list_and_index(&config.out_dir, &docs, config.list_backups, list_backed_up);
+
+    if let Some(e) = doc_ids.error {
+        return Err(e.context("listing docs failed partway through; run again to get the rest"));
+    }
+
+    if interrupted() {
+        return Err(anyhow!("interrupted; saved {} docs to {}; run again to resume",
+            docs.docs.len(), config.out_dir.join("list.json").display()));
+    }
+
+    Ok(Some((docs, broken)))
+}
+
+/// For `--check-links`: add any broken links in the index to the ones found in the docs, and list
+/// them.
+fn report_broken_links(config: &Config, mut broken: Vec<BrokenLink>) -> Result<()> {
+    let index_path = fs::canonicalize(config.out_dir.join("index.html"))?;
+    broken.extend(broken_links_in(&index_path, &config.img_re, &config.link_re)?);
+    for link in &broken {
+        println!("broken link in {:?}: {}", link.file, link.target);
+    }
+    if broken.is_empty() {
+        info!("all local links are OK");
+    } else {
+        warn!("found {} broken local links", broken.len());
+        if config.strict {
+            return Err(anyhow!("broken links found"));
+        }
+    }
+    Ok(())
+}
+
+/// For `--upload`: copy everything in the output directory to `dest`.
+fn upload_output(config: &Config, dest: &upload::Destination) -> Result<()> {
+    let failed = upload::upload_dir(&config.out_dir, dest, &config.agent, config.upload_logs)
+        .map_err(|e| anyhow!(e))?;
+    if failed != 0 {
+        return Err(anyhow!("{} files failed to upload", failed));
+    }
+    Ok(())
+}
+
+/// Create a folder if it doesn't exist, and make sure files can be written in it.
+fn check_writable(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    fs::write(&probe, b"")
+        .with_context(|| format!("can't write files in {}", dir.display()))?;
+    fs::remove_file(&probe)
+        .with_context(|| format!("can't remove files from {}", dir.display()))?;
+    Ok(())
+}
+
+/// Local references from a doc to some other file that doesn't exist.
+struct BrokenLink {
+    file: PathBuf,
+    target: String,
+}
+
+/// Find all links and images in a doc that point at local files which don't exist.
+fn broken_links_in(path: &Path, img_re: &Regex, link_re: &Regex) -> Result<Vec<BrokenLink>> {
+    let html = read_doc(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let base = Url::from_file_path(path)
+        .map_err(|()| anyhow!("can't make a URL from {}", path.display()))?;
+    let mut broken = vec![];
+    for m in img_re.captures_iter(&html).chain(link_re.captures_iter(&html)) {
+        let target = String::from_utf8_lossy(captured_url(&m)).into_owned();
+        if target.starts_with('#') || target.starts_with("//") {
+            continue;
+        }
+        // Anything with a scheme (http:, data:, mailto:, ...) isn't a local reference.
+        if Url::parse(&target).is_ok() {
+            continue;
+        }
+        let exists = base.join(&target).ok()
+            .and_then(|url| url.to_file_path().ok())
+            .map(|local| local.exists())
+            .unwrap_or(false);
+        if !exists {
+            broken.push(BrokenLink { file: path.to_owned(), target });
+        }
+    }
+    Ok(broken)
+}
+
+/// Scan every HTML doc in the dump for any local links or images that don't resolve. The index
+/// isn't included, since it gets regenerated at the end of the run.
+fn check_local_links(dir: &Path, docs: &[DocInfo], img_re: &Regex, link_re: &Regex)
+    -> Result<Vec<BrokenLink>>
+{
+    let dir = fs::canonicalize(dir).with_context(|| format!("can't find {}", dir.display()))?;
+    let mut broken = vec![];
+    for doc in docs {
+        let path = dir.join(&doc.path);
+        if !is_html_doc(&doc.path) || !path.exists() {
+            continue;
+        }
+        broken.extend(broken_links_in(&path, img_re, link_re)?);
+    }
+    Ok(broken)
+}
+
+/// Move docs with broken links into a `quarantine` subfolder so they're out of the way of the good
+/// ones, and update their paths in the doc list.
+fn quarantine_docs(
+    dir: &Path,
+    docs: &mut [DocInfo],
+    broken: &[BrokenLink],
+    config: &Config,
+    link_re: &Regex,
+) -> Result<()> {
+    let qdir = dir.join("quarantine");
+    let mut moved = 0;
+    for doc in docs.iter_mut() {
+        if doc.path.starts_with("quarantine/")
+            || !broken.iter().any(|link| link.file.ends_with(&doc.path))
+        {
+            continue;
+        }
+        let dest = qdir.join(&doc.path);
+        let dest_dir = dest.parent().unwrap();
+        fs::create_dir_all(dest_dir)
+            .with_context(|| format!("failed to create {}", dest_dir.display()))?;
+        let src = dir.join(&doc.path);
+        let html = read_doc(&src).with_context(|| format!("failed to read {}", src.display()))?;
+        // It's one level deeper now, so everything it refers to is one more level up.
+        let html = prefix_relative_refs(&html, config, link_re, b"../");
+        write_doc(&dest, &html).with_context(|| format!("failed to write {}", dest.display()))?;
+        fs::remove_file(&src).with_context(|| format!("failed to remove {}", src.display()))?;
+        info!("quarantined {:?}", doc.path);
+        doc.path = format!("quarantine/{}", doc.path);
+        moved += 1;
+    }
+    if moved != 0 {
+        info!("moved {} docs with broken links to {}", moved, qdir.display());
+    }
+    Ok(())
+}
+
+/// Whether a link or image URL refers to something relative to the doc it's in: anything without a
+/// scheme, that isn't just a `#fragment` and doesn't start from the root.
+fn is_relative_ref(target: &[u8]) -> bool {
+    let target = match std::str::from_utf8(target) {
+        Ok(target) => target.trim(),
+        Err(_) => return false,
+    };
+    !target.is_empty()
+        && !target.starts_with('#')
+        && !target.starts_with('/')
+        && Url::parse(target) == Err(url::ParseError::RelativeUrlWithoutBase)
+}
+
+/// Find every relative reference in a doc, in links, image `src` and `srcset` attributes, and
+/// `url()`s in `style` attributes, and return where each one starts, in order.
+fn relative_refs(html: &[u8], config: &Config, link_re: &Regex) -> Vec<usize> {
+    let mut refs = vec![];
+    for m in config.img_re.captures_iter(html).chain(link_re.captures_iter(html)) {
+        let url = m.name("url")
+            .or_else(|| m.name("url_sq"))
+            .or_else(|| m.name("url_bare"))
+            .unwrap();
+        if is_relative_ref(url.as_bytes()) {
+            refs.push(url.start());
+        }
+    }
+    for m in config.srcset_re.captures_iter(html) {
+        let value = m.name("url").or_else(|| m.name("url_sq")).unwrap();
+        let srcset = match std::str::from_utf8(value.as_bytes()) {
+            Ok(srcset) => srcset,
+            Err(_) => continue,
+        };
+        for (range, _) in srcset_candidates(srcset) {
+            if is_relative_ref(srcset[range.clone()].as_bytes()) {
+                refs.push(value.start() + range.start);
+            }
+        }
+    }
+    for tag in config.style_re.captures_iter(html) {
+        let style = tag.name("style").or_else(|| tag.name("style_sq")).unwrap();
+        for m in config.css_url_re.captures_iter(style.as_bytes()) {
+            let url = ["url", "url_dq", "url_sq", "url_ent"].iter()
+                .find_map(|name| m.name(name))
+                .unwrap();
+            if is_relative_ref(url.as_bytes()) {
+                refs.push(style.start() + url.start());
+            }
+        }
+    }
+    refs.sort_unstable();
+    refs.dedup();
+    refs
+}
+
+/// Put `prefix` in front of every relative reference in a doc, as found by [`relative_refs`].
+fn prefix_relative_refs(html: &[u8], config: &Config, link_re: &Regex, prefix: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(html.len());
+    let mut last_end = 0;
+    for pos in relative_refs(html, config, link_re) {
+        out.extend_from_slice(&html[last_end .. pos]);
+        out.extend_from_slice(prefix);
+        last_end = pos;
+    }
+    out.extend_from_slice(&html[last_end ..]);
+    out
+}
+
+/// Report docs from previous runs that weren't in this run's listing, so have presumably been
+/// deleted from Paper (or are no longer shared with this account). With `prune`, delete their files
+/// and drop them from the list too.
+fn find_deleted_docs(dir: &Path, docs: &mut Vec<DocInfo>, listed: &HashSet<String>, prune: bool)
+    -> Result<()>
+{
+    let is_gone = |doc: &DocInfo| !doc.id.is_empty() && !listed.contains(&doc.id);
+    let mut gone = 0;
+    for doc in docs.iter().filter(|doc| is_gone(doc)) {
+        println!("gone from Paper: {:?} ({})", doc.path, doc.url);
+        gone += 1;
+        if !prune {
+            continue;
+        }
+        let path = dir.join(&doc.path);
+        let mut paths = vec![];
+        if path.extension() == Some("html".as_ref()) {
+            // With --format both, there's Markdown alongside.
+            paths.push(path.with_extension("md"));
+        }
+        paths.push(raw_path(&path, OutputFormat::Html));
+        paths.push(raw_path(&path, OutputFormat::Markdown));
+        paths.push(path);
+        for path in paths {
+            match fs::remove_file(&path) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e).with_context(|| format!("failed to remove {}", path.display())),
+            }
+        }
+    }
+    if gone == 0 {
+        return Ok(());
+    }
+    if prune {
+        docs.retain(|doc| !is_gone(doc));
+        info!("pruned {} docs that are gone from Paper", gone);
+    } else {
+        warn!("{} docs are gone from Paper; use --prune to delete them", gone);
+    }
+    Ok(())
+}
+
+/// If `url` is a link to a Paper doc, get the doc's ID and the link's `#fragment`, if any. Paper
+/// links usually put the title in front of the ID, like `/doc/Some-Title-<id>`.
+fn paper_doc_link(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("https://paper.dropbox.com/doc/")
+        .or_else(|| url.strip_prefix("http://paper.dropbox.com/doc/"))?;
+    let fragment = rest.find('#').map(|i| &rest[i..]).unwrap_or("");
+    let segment = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let id = segment.rsplit('-').next().filter(|id| !id.is_empty())?;
+    Some((id, fragment))
+}
+
+/// Point links between docs in the dump at the local files instead of Paper, so the dump can be
+/// browsed offline. Links to docs that aren't in the dump are left as they are.
+fn link_docs_locally(dir: &Path, docs: &[DocInfo], link_re: &Regex) -> Result<()> {
+    let paths = docs.iter()
+        .filter(|doc| !doc.id.is_empty())
+        .map(|doc| (doc.id.as_str(), doc.path.as_str()))
+        .collect::<HashMap<_, _>>();
+    let mut changed = 0;
+    for doc in docs {
+        let path = dir.join(&doc.path);
+        if !is_html_doc(&doc.path) || !path.exists() {
+            continue;
+        }
+        let html = read_doc(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        let up = "../".repeat(doc.path.matches('/').count());
+        let mut out = Vec::with_capacity(html.len());
+        let mut last_end = 0;
+        for c in link_re.captures_iter(&html) {
+            let m = c.name("url").unwrap();
+            let target = std::str::from_utf8(m.as_bytes()).ok()
+                .and_then(paper_doc_link)
+                // The header links back to the doc itself on Paper; keep that one.
+                .filter(|(id, _)| *id != doc.id)
+                .and_then(|(id, fragment)| Some((paths.get(id)?, fragment)));
+            if let Some((target, fragment)) = target {
+                out.extend_from_slice(&html[last_end .. m.start()]);
+                out.extend_from_slice(format!("{}{}{}", up, path_to_url(target), fragment).as_bytes());
+                last_end = m.end();
+            }
+        }
+        if last_end == 0 {
+            continue;
+        }
+        out.extend_from_slice(&html[last_end ..]);
+        write_doc(&path, &out).with_context(|| format!("failed to write {}", path.display()))?;
+        changed += 1;
+    }
+    if changed != 0 {
+        info!("pointed links in {} docs at the local copies", changed);
+    }
+    Ok(())
+}
+
+/// Write list.json and index.html, backing up the old ones first. `list_backed_up` says that
+/// list.json has already been backed up this run.
+fn write_list_and_index(dir: &Path, docs: &DocList, list_backups: usize, list_backed_up: bool) {
+    let list_path = dir.join("list.json");
+    if !list_backed_up {
+        if let Err(e) = backup_file(&list_path, list_backups) {
+            warn!("failed to back up {}: {}", list_path.display(), e);
+        }
+    }
+    write_doc_list(&list_path, docs).expect("failed to write list.json");
+
+    let index_path = dir.join("index.html");
+    if let Err(e) = backup_file(&index_path, list_backups) {
+        warn!("failed to back up {}: {}", index_path.display(), e);
+    }
+    write_atomically(&index_path, |index| write_index(index, docs))
+        .expect("failed to write index.html");
+}
+
+/// Write list.json, indented so that changes between runs make for readable diffs.
+fn write_doc_list(path: &Path, docs: &DocList) -> io::Result<()> {
+    write_atomically(path, |out| {
+        serde_json::to_writer_pretty(&mut *out, docs)?;
+        writeln!(out)
+    })
+}
+
+fn write_index(index: &mut dyn Write, docs: &DocList) -> io::Result<()> {
+    writeln!(index, "<html><head><title>Paper Doc Index</title></head><body>")?;
+    writeln!(index, "<p><input id=\"search\" type=\"search\" placeholder=\"Filter by title or owner\" autofocus></p>")?;
+    // Group by folder, but only if there are any; lists from older versions don't have them.
+    let grouped = docs.docs.iter().any(|doc| !doc.folder.is_empty());
+    let mut sorted = docs.docs.iter().collect::<Vec<_>>();
+    if grouped {
+        // Stable, so each folder stays sorted by name.
+        sorted.sort_by(|a, b| a.folder.cmp(&b.folder));
+    }
+    let mut folder = None;
+    for doc in sorted {
+        if grouped && folder != Some(&doc.folder) {
+            writeln!(index, "<h2>{}</h2>",
+                if doc.folder.is_empty() { "(no folder)" } else { &doc.folder })?;
+            folder = Some(&doc.folder);
+        }
+        writeln!(index, "<p class=\"doc\"><a href=\"{}\">{}</a><br><small>{}</small> &middot; <small><a href=\"{}\">link</a></small></p>",
+            path_to_url(&doc.path),
+            doc.name,
+            doc.owner,
+            doc.url,
+        )?;
+    }
+    writeln!(index, "<script>{}</script>", INDEX_SCRIPT)?;
+    writeln!(index, "</body></html>")
+}
+
+/// Filters the index as you type in the search box, hiding folder headings with nothing left
+/// under them.
+const INDEX_SCRIPT: &str = "
+document.getElementById('search').addEventListener('input', function () {
+  var words = this.value.toLowerCase().split(/\\s+/).filter(Boolean);
+  var heading = null, shown = false;
+  function finish() { if (heading) heading.style.display = shown ? '' : 'none'; }
+  Array.prototype.forEach.call(document.querySelectorAll('h2, p.doc'), function (el) {
+    if (el.tagName === 'H2') { finish(); heading = el; shown = false; return; }
+    var text = el.textContent.toLowerCase();
+    var match = words.every(function (w) { return text.indexOf(w) !== -1; });
+    el.style.display = match ? '' : 'none';
+    shown = shown || match;
+  });
+  finish();
+});
+";
+
+/// How many docs to finish between saving list.json during a run.
+const CHECKPOINT_EVERY: usize = 50;
+
+/// Write a file by writing a temporary one next to it and renaming it into place, so that if this
+/// gets interrupted the file is left as either the old version or the new one, never part of one.
+fn write_atomically(path: &Path, write: impl FnOnce(&mut dyn Write) -> io::Result<()>)
+    -> io::Result<()>
+{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let result = File::create(&tmp)
+        .and_then(|file| {
+            let mut out = io::BufWriter::new(file);
+            write(&mut out)?;
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Whether a doc's file is HTML, compressed (with `--gzip`) or not.
+fn is_html_doc(path: &str) -> bool {
+    path.ends_with(".html") || path.ends_with(".html.gz")
+}
+
+/// Read a doc's file, decompressing it if it's a `.gz`.
+fn read_doc(path: &Path) -> io::Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    if path.extension() == Some("gz".as_ref()) {
+        gzip::decompress(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Replace a doc's file, compressing it if it's a `.gz`.
+fn write_doc(path: &Path, data: &[u8]) -> io::Result<()> {
+    if path.extension() == Some("gz".as_ref()) {
+        write_atomically(path, |f| f.write_all(&gzip::compress(data)))
+    } else {
+        write_atomically(path, |f| f.write_all(data))
+    }
+}
+
+/// Reconstruct the doc list from the HTML files (compressed or not) in `dir` and its subfolders,
+/// using the title and the header that `fetch_doc` puts at the top of each one. Folder names are
+/// taken from the subfolders, so they're only approximate.
+fn rebuild_doc_list(dir: &Path) -> Result<DocList> {
+    let header_re = Regex::new(r#"(?s)<title>(?P<title>.*?)</title>.*?<p>downloaded rev (?P<rev>\d+)(?: on [^<]*?)? from <a href="(?P<url>[^"]+)">[^<]*</a><br>\s*owned by (?P<owner>.*?)</p>"#)
+        .unwrap();
+    let mut docs = vec![];
+    let mut subdirs = vec![String::new()];
+    while let Some(subdir) = subdirs.pop() {
+        let entries = fs::read_dir(dir.join(&subdir))
+            .with_context(|| format!("failed to read {}", dir.join(&subdir).display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            let rel = if subdir.is_empty() { name.clone() } else { format!("{}/{}", subdir, name) };
+            if entry.file_type()?.is_dir() {
+                if rel != "images" && rel != "zips" {
+                    subdirs.push(rel);
+                }
+                continue;
+            }
+            if !is_html_doc(&name) || name.ends_with(".raw.html") || rel == "index.html" {
+                continue;
+            }
+            let html = read_doc(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let c = match header_re.captures(&html) {
+                Some(c) => c,
+                None => {
+                    warn!("{}: no header found; skipping", path.display());
+                    continue;
+                }
+            };
+            let text = |group| String::from_utf8_lossy(&c[group]).into_owned();
+            let mut doc = DocInfo {
+                id: String::new(),
+                url: text("url"),
+                name: text("title"),
+                owner: text("owner"),
+                folder: subdir.split('/')
+                    .skip_while(|c| *c == "quarantine")
+                    .collect::<Vec<_>>()
+                    .join(" / "),
+                path: rel,
+                revision: text("rev").parse().ok(),
+                // Can't be told from the processed file.
+                content_hash: None,
+            };
+            doc.fill_id();
+            docs.push(doc);
+        }
+    }
+    docs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(DocList { docs, validator: None })
+}
+
+/// Print a summary of a finished dump: how many docs there are and how big, broken down by owner
+/// and folder, and the same for images.
+fn print_stats(dir: &Path, json: bool) -> Result<()> {
+    let list_path = dir.join("list.json");
+    let list: DocList = serde_json::from_reader(File::open(&list_path)
+            .with_context(|| format!("failed to open {}", list_path.display()))?)
+        .with_context(|| format!("failed to parse {}", list_path.display()))?;
+
+    let mut doc_bytes = 0;
+    let mut missing = 0;
+    let mut by_owner = std::collections::BTreeMap::<&str, usize>::new();
+    let mut by_folder = std::collections::BTreeMap::<&str, usize>::new();
+    for doc in &list.docs {
+        match fs::metadata(dir.join(&doc.path)) {
+            Ok(m) => doc_bytes += m.len(),
+            Err(_) => missing += 1,
+        }
+        *by_owner.entry(&doc.owner).or_default() += 1;
+        let folder = if doc.folder.is_empty() { "(no folder)" } else { &doc.folder };
+        *by_folder.entry(folder).or_default() += 1;
+    }
+
+    let mut images = 0;
+    let mut image_bytes = 0;
+    match fs::read_dir(dir.join("images")) {
+        Ok(entries) => for entry in entries {
+            let m = entry?.metadata()?;
+            if m.is_file() {
+                images += 1;
+                image_bytes += m.len();
+            }
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e).context("failed to read images directory"),
+    }
+
+    if json {
+        let stats = serde_json::json!({
+            "docs": list.docs.len(),
+            "doc_bytes": doc_bytes,
+            "missing_docs": missing,
+            "images": images,
+            "image_bytes": image_bytes,
+            "docs_per_owner": by_owner,
+            "docs_per_folder": by_folder,
+        });
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+
+    println!("{:<30} {:>12}", "docs", list.docs.len());
+    println!("{:<30} {:>12}", "doc bytes", doc_bytes);
+    if missing != 0 {
+        println!("{:<30} {:>12}", "docs missing from disk", missing);
+    }
+    println!("{:<30} {:>12}", "images", images);
+    println!("{:<30} {:>12}", "image bytes", image_bytes);
+    for (heading, counts) in &[("owner", &by_owner), ("folder", &by_folder)] {
+        println!();
+        println!("{:<30} {:>12}", heading, "docs");
+        let mut counts = counts.iter().collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(a.1));
+        for (name, n) in counts {
+            println!("{:<30} {:>12}", name, n);
+        }
+    }
+    Ok(())
+}
+
+/// Resolve a relative link found in the file at `from` (itself relative to the dump directory) to
+/// a path relative to the dump directory. Returns `None` if it points outside of it.
+fn resolve_relative(from: &str, link: &str) -> Option<String> {
+    let mut parts = from.split('/').collect::<Vec<_>>();
+    parts.pop();
+    for part in link.split('/') {
+        match part {
+            "." | "" => (),
+            ".." => { parts.pop()?; }
+            part => parts.push(part),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+/// Pack everything in the output folder into one zip file, apart from the per-folder zips and any
+/// half-written temporary files. Files are read one at a time, so only the biggest one needs to
+/// fit in memory. The zip file is written under a temporary name and renamed when it's complete.
+fn write_dump_zip(dir: &Path, zip_path: &Path, config: &Config) -> Result<()> {
+    let mut files = vec![];
+    upload::walk(dir, Path::new(""), &mut files, &mut vec![])
+        .with_context(|| format!("failed to read {}", dir.display()))?;
+    files.sort();
+
+    // In case it's being written inside the folder being zipped. It might not exist yet, but its
+    // folder has to.
+    let zip_canonical = zip_path.parent()
+        .map(|parent| if parent.as_os_str().is_empty() { Path::new(".") } else { parent })
+        .and_then(|parent| fs::canonicalize(parent).ok())
+        .zip(zip_path.file_name())
+        .map(|(parent, name)| parent.join(name));
+    let time = if config.date_stamp { Some(config.now_naive()) } else { None };
+    let mut count = 0;
+    write_atomically(zip_path, |out| {
+        let mut zip = zip::ZipWriter::new(out, time);
+        for rel in files {
+            let path = dir.join(&rel);
+            if rel.starts_with("zips")
+                || rel.extension() == Some("tmp".as_ref())
+                || fs::canonicalize(&path).ok() == zip_canonical
+            {
+                continue;
+            }
+            let name = rel.iter().map(|c| c.to_string_lossy()).collect::<Vec<_>>().join("/");
+            let data = fs::read(&path).map_err(|e| {
+                io::Error::new(e.kind(), format!("failed to read {}: {}", path.display(), e))
+            })?;
+            zip.add(&name, &data)?;
+            count += 1;
+        }
+        zip.finish().map(drop)
+    }).with_context(|| format!("failed to write {}", zip_path.display()))?;
+    info!("packed {} files into {}", count, zip_path.display());
+    Ok(())
+}
+
+/// Write a zip file for each top-level folder, containing its docs (including those in
+/// subfolders) along with their images and an index, and an index of the zip files.
+fn write_folder_zips(dir: &Path, docs: &[DocInfo], config: &Config) -> Result<()> {
+    let mut groups = std::collections::BTreeMap::<&str, Vec<&DocInfo>>::new();
+    for doc in docs {
+        let top = doc.folder.split(" / ").next().filter(|f| !f.is_empty()).unwrap_or("(no folder)");
+        groups.entry(top).or_default().push(doc);
+    }
+
+    let zips_dir = dir.join("zips");
+    fs::create_dir_all(&zips_dir)
+        .with_context(|| format!("failed to create {}", zips_dir.display()))?;
+    let time = if config.date_stamp { Some(config.now_naive()) } else { None };
+    let mut index = String::from("<html><head><title>Paper Folders</title></head><body>\n");
+
+    for (folder, docs) in groups {
+        let name = folder_dir_name(folder) + ".zip";
+        let zip_path = zips_dir.join(&name);
+        write_atomically(&zip_path, |out| {
+            let mut zip = zip::ZipWriter::new(out, time);
+            let mut added = std::collections::HashSet::new();
+            let mut folder_index = format!("<html><head><title>{}</title></head><body>\n", folder);
+
+            for doc in &docs {
+                let path = dir.join(&doc.path);
+                let read_error = |e: io::Error| {
+                    io::Error::new(e.kind(), format!("failed to read {}: {}", doc.path, e))
+                };
+                // The doc goes in as it is, but with --gzip it has to be decompressed to find the
+                // images in it.
+                let data = fs::read(&path).map_err(read_error)?;
+                let html = read_doc(&path).map_err(read_error)?;
+                for c in config.img_re.captures_iter(&html) {
+                    let src = String::from_utf8_lossy(captured_url(&c)).into_owned();
+                    if src.contains("://") {
+                        continue;
+                    }
+                    if let Some(image) = resolve_relative(&doc.path, &src) {
+                        if added.contains(&image) {
+                            continue;
+                        }
+                        match fs::read(dir.join(&image)) {
+                            Ok(image_data) => zip.add(&image, &image_data)?,
+                            Err(e) => warn!("{}: failed to read image {}: {}", name, image, e),
+                        }
+                        added.insert(image);
+                    }
+                }
+                zip.add(&doc.path, &data)?;
+                folder_index += &format!("<p><a href=\"{}\">{}</a><br><small>{}</small></p>\n",
+                    path_to_url(&doc.path), doc.name, doc.folder);
+            }
+            folder_index += "</body></html>\n";
+            zip.add("index.html", folder_index.as_bytes())?;
+            zip.finish().map(drop)
+        }).with_context(|| format!("failed to write {}", zip_path.display()))?;
+
+        index += &format!("<p><a href=\"{}\">{}</a> ({} docs)</p>\n",
+            path_to_url(&name), folder, docs.len());
+        info!("wrote {} docs to {}", docs.len(), zip_path.display());
+    }
+
+    index += "</body></html>\n";
+    let index_path = zips_dir.join("index.html");
+    write_atomically(&index_path, |f| f.write_all(index.as_bytes()))
+        .with_context(|| format!("failed to write {}", index_path.display()))?;
+    Ok(())
+}
+
+/// Move a file aside before it gets overwritten, keeping up to `keep` old copies: `FILE.bak` is
+/// the most recent, then `FILE.bak.2`, and so on.
+fn backup_file(path: &Path, keep: usize) -> io::Result<()> {
+    if keep == 0 || !path.exists() {
+        return Ok(());
+    }
+    let backup = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".bak");
+        if n > 1 {
+            name.push(format!(".{}", n));
+        }
+        PathBuf::from(name)
+    };
+    match fs::remove_file(backup(keep)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    for n in (1 .. keep).rev() {
+        match fs::rename(backup(n), backup(n + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+    fs::copy(path, backup(1))?;
+    Ok(())
+}
+
+/// Percent-encode a relative path for use in a link, leaving the slashes alone.
+fn path_to_url(path: &str) -> String {
+    path.split('/')
+        .map(|part| url::form_urlencoded::byte_serialize(part.as_bytes())
+            .collect::<String>()
+            .replace('+', "%20"))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+const AUTH_FAILED: &str = "authentication failed — re-run to re-authorize";
+
+/// Give errors that can't be fixed by retrying a clearer explanation.
+fn explain_error(e: dropbox_sdk::Error) -> anyhow::Error {
+    match e {
+        dropbox_sdk::Error::Authentication(e) => anyhow!("{}: {}", AUTH_FAILED, e),
+        e => e.into(),
+    }
+}
+
+/// Doc ids from the listing, fetching each page of it only when the previous one has been used
+/// up. If getting a page fails, iteration stops and the error is kept in `error`.
+struct DocIds<'a> {
+    client: &'a dyn PaperSource,
+    page: std::vec::IntoIter<String>,
+    /// Where the next page starts, if there is one.
+    cursor: Option<String>,
+    /// How many ids have been listed so far.
+    listed: Arc<AtomicUsize>,
+    error: Option<anyhow::Error>,
+}
+
+impl<'a> DocIds<'a> {
+    fn new(client: &'a dyn PaperSource, first: ListPaperDocsResponse) -> Self {
+        Self {
+            client,
+            listed: Arc::new(AtomicUsize::new(first.doc_ids.len())),
+            cursor: if first.has_more { Some(first.cursor.value) } else { None },
+            page: first.doc_ids.into_iter(),
+            error: None,
+        }
+    }
+
+    /// Whether every id has been listed, without any errors.
+    fn finished(&self) -> bool {
+        self.error.is_none() && self.cursor.is_none() && self.page.len() == 0
+    }
+
+    /// Just the one id, without listing anything.
+    fn single(client: &'a dyn PaperSource, id: String) -> Self {
+        Self {
+            client,
+            page: vec![id].into_iter(),
+            cursor: None,
+            listed: Arc::new(AtomicUsize::new(1)),
+            error: None,
+        }
+    }
+}
+
+impl<'a> Iterator for DocIds<'a> {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        loop {
+            if let Some(id) = self.page.next() {
+                return Some(id);
+            }
+            let cursor = self.cursor.take()?;
+            let next = self.client.list_continue(&cursor)
+                .map_err(explain_error)
+                .context("paper/docs/list/continue HTTP or transport err")
+                .and_then(|r| r.context("paper/docs/list/continue API err"));
+            match next {
+                Ok(result) => {
+                    self.listed.fetch_add(result.doc_ids.len(), Ordering::SeqCst);
+                    self.cursor = if result.has_more { Some(result.cursor.value) } else { None };
+                    self.page = result.doc_ids.into_iter();
+                }
+                Err(e) => {
+                    self.error = Some(e);
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Something that changes whenever a doc is added or edited: the most recently modified doc's ID
+/// and revision. Paper has no conditional requests or change cursors, so this is as close as it
+/// gets. Returns `None` if it couldn't be determined, in which case a full run is needed.
+fn listing_validator(
+    client: &dyn PaperSource,
+    config: &Config,
+    list_args: &ListPaperDocsArgs,
+) -> Option<String> {
+    let args = list_args.clone()
+        .with_limit(1)
+        .with_sort_by(ListPaperDocsSortBy::Modified)
+        .with_sort_order(ListPaperDocsSortOrder::Descending);
+    let id = match client.list(&args) {
+        Ok(Ok(result)) => result.doc_ids.into_iter().next()?,
+        Ok(Err(e)) => match e {},
+        Err(e) => {
+            warn!("failed to check for changes: {}", e);
+            return None;
+        }
+    };
+    let mut output = String::new();
+    match download_doc(&id, client, config, false, ExportFormat::Html, &mut output) {
+        Some(result) => Some(format!("{}@{}", id, result.result.revision)),
+        None => {
+            warn!("failed to check for changes: {}", output.trim_end());
+            None
+        }
+    }
+}
+
+/// Like [`explain_error`], but also recognizing the ways the Paper API fails once it's no longer
+/// available, which is worth explaining since everything here depends on it.
+fn explain_list_error(e: dropbox_sdk::Error) -> anyhow::Error {
+    let gone = match &e {
+        dropbox_sdk::Error::BadRequest(msg) => {
+            // Every error names the route, so the message mentioning Paper doesn't mean anything.
+            let msg = msg.to_ascii_lowercase();
+            msg.contains("unknown api function") || msg.contains("deprecated")
+        }
+        dropbox_sdk::Error::UnexpectedHttpError { code, .. } => *code == 404 || *code == 410,
+        dropbox_sdk::Error::AccessDenied(AccessError::PaperAccessDenied(
+            PaperAccessError::PaperDisabled)) => true,
+        _ => false,
+    };
+    if gone {
+        anyhow!("{}\n\nThe Paper API this tool uses has been deprecated by Dropbox, and it looks \
+            like it is no longer available for this account. Accounts whose Paper docs have been \
+            migrated to .paper files in Dropbox can't be dumped this way; download those from \
+            Dropbox directly instead.", e)
+    } else {
+        explain_error(e)
+    }
+}
+
+/// Export a doc, retrying on server and transport errors. If `with_body` is false, only the
+/// metadata is fetched. Returns `None` if the doc couldn't be fetched; the reason is added to the
+/// output.
+fn download_doc(
+    id: &str,
+    client: &dyn PaperSource,
+    config: &Config,
+    with_body: bool,
+    format: ExportFormat,
+    output: &mut String,
+) -> Option<HttpRequestResult<PaperDocExportResult>> {
+    let mut failures = 0;
+    loop {
+        let mut retry_after = None;
+        let download_result = client.download(id, format.clone(), with_body);
+        match download_result {
+            Ok(Ok(result)) if !with_body => return Some(result),
+            Ok(Ok(mut result)) => {
+                let mut html = vec![];
+                match result.body.as_mut().expect("response must have body").read_to_end(&mut html) {
+                    Ok(_) if looks_like_error_page(&html) => {
+                        warn!("{}: response looks like a Dropbox error page, not the doc; retrying",
+                            id);
+                        config.log_retry("doc", id, failures + 1, "error-page");
+                    }
+                    Ok(_) => {
+                        result.body = Some(Box::new(io::Cursor::new(html)));
+                        return Some(result);
+                    }
+                    Err(e) => {
+                        warn!("{}: I/O error reading doc: {}; retrying", id, e);
+                        config.log_retry("doc", id, failures + 1, "io");
+                    }
+                }
+            }
+            Ok(Err(api_err)) => {
+                *output += &format!("API error: {}\n", api_err);
+                // Not retriable. Skip this doc.
+                return None;
+            }
+            Err(dropbox_sdk::Error::Authentication(e)) => {
+                *output += &format!("authentication error: {}\n", e);
+                // Not retriable, and every other doc is going to fail the same way.
+                if config.abort_on_auth_error && !config.aborting.swap(true, Ordering::SeqCst) {
+                    error!("{}; stopping", AUTH_FAILED);
+                }
+                return None;
+            }
+            Err(dropbox_sdk::Error::ServerError(_)) => {
+                // Don't print the error; it's got a big HTML page text in it.
+                warn!("{}: HTTP 503; retrying", id);
+                config.log_retry("doc", id, failures + 1, "server-error");
+            }
+            Err(dropbox_sdk::Error::RateLimited { reason, retry_after_seconds }) => {
+                warn!("{}: rate limited ({}); retrying", id, reason);
+                config.log_retry("doc", id, failures + 1, "rate-limited");
+                if retry_after_seconds != 0 {
+                    retry_after = Some(Duration::from_secs(retry_after_seconds.into()));
+                }
+            }
+            Err(e) => {
+                warn!("{}: HTTP transport error: {}; retrying", id, e);
+                let category = match e {
+                    dropbox_sdk::Error::HttpClient(_) => "transport",
+                    dropbox_sdk::Error::UnexpectedHttpError { .. } => "http-status",
+                    _ => "other",
+                };
+                config.log_retry("doc", id, failures + 1, category);
+            }
+        }
+        failures += 1;
+        if failures > config.max_retries {
+            *output += "too many errors; skipping doc\n";
+            config.gave_up.lock().unwrap().push(id.to_owned());
+            return None;
+        }
+        if !config.retry_budget.take() {
+            *output += "retry budget exhausted; skipping doc\n";
+            return None;
+        }
+        // Dropbox says how long to wait when it's rate limiting; otherwise guess.
+        let attempt = if config.final_pass.load(Ordering::SeqCst) { failures + 2 } else { failures };
+        thread::sleep(retry_after.unwrap_or_else(|| backoff(attempt)));
+    }
+}
+
+/// Text that shows up in Dropbox's error and maintenance pages.
+const ERROR_PAGE_MARKERS: &[&[u8]] = &[
+    b"<title>Dropbox - Error",
+    b"<title>Dropbox - 5",
+    b"<title>Dropbox - Maintenance",
+    b"Dropbox is down for scheduled maintenance",
+];
+
+/// Whether a successful-looking download is really one of Dropbox's error pages. This errs on the
+/// side of trusting the download: error pages are full HTML documents, so anything with a marker
+/// that's also big enough to be a real doc that just mentions one is let through.
+fn looks_like_error_page(html: &[u8]) -> bool {
+    html.len() < 64 * 1024
+        && html.windows(b"<html".len()).take(1024).any(|w| w.eq_ignore_ascii_case(b"<html"))
+        && ERROR_PAGE_MARKERS.iter().any(|m| html.windows(m.len()).any(|w| w == *m))
+}
+
+/// Names of the Paper folders containing a doc, starting from the root.
+fn doc_folders(client: &dyn PaperSource, id: &str, output: &mut String) -> Vec<String> {
+    match client.folder_info(id) {
+        Ok(Ok(info)) => info.folders.unwrap_or_default()
+            .into_iter()
+            .map(|f| f.name)
+            .collect(),
+        Ok(Err(e)) => {
+            *output += &format!("API error getting folder info: {}\n", e);
+            vec![]
+        }
+        Err(e) => {
+            *output += &format!("HTTP error getting folder info: {}\n", e);
+            vec![]
+        }
+    }
+}
+
+/// Metadata for a doc, as written by `--metadata-only`.
+#[derive(Serialize)]
+struct DocMetadata {
+    id: String,
+    url: String,
+    title: String,
+    owner: String,
+    revision: i64,
+    /// Names of the Paper folders containing the doc, starting from the root.
+    folders: Vec<String>,
+}
+
+/// A doc as printed by `--list-only`: the same as in list.json, but without the local path.
+#[derive(Serialize)]
+struct ListedDoc {
+    id: String,
+    url: String,
+    name: String,
+    owner: String,
+    folder: String,
+    revision: i64,
+}
+
+/// Fetch the metadata of all the given docs, without any bodies or images, sorted by title.
+/// Progress is logged, so it stays out of anything written to stdout.
+fn fetch_metadata(
+    ids: Vec<String>,
+    client: Arc<dyn PaperSource>,
+    config: Arc<Config>,
+) -> Result<Vec<DocMetadata>> {
+    let pool = ThreadPool::new(config.page_threads);
+    let all = Arc::new(Mutex::new(vec![]));
+    for id in ids.into_iter() {
+        let client = Arc::clone(&client);
+        let config = Arc::clone(&config);
+        let all = Arc::clone(&all);
+        pool.execute(move || {
+            let url = format!("https://paper.dropbox.com/doc/{}", id);
+            let mut output = url.clone() + "\n";
+            if config.aborting.load(Ordering::SeqCst) {
+                return;
+            }
+            let result = download_doc(&id, &*client, &config, false, ExportFormat::Html, &mut output);
+            let failed = result.is_none();
+            if let Some(result) = result {
+                let folders = doc_folders(&*client, &id, &mut output);
+                output += &format!("title: {}\nowner: {}\n", result.result.title, result.result.owner);
+                all.lock().unwrap().push(DocMetadata {
+                    id,
+                    url,
+                    title: result.result.title,
+                    owner: result.result.owner,
+                    revision: result.result.revision,
+                    folders,
+                });
+            }
+            if failed {
+                warn!("{}", output.trim_end());
+            } else {
+                info!("{}", output.trim_end());
+            }
+        });
+    }
+    pool.join();
+
+    if config.aborting.load(Ordering::SeqCst) {
+        return Err(anyhow!(AUTH_FAILED));
+    }
+
+    let mut all = Arc::try_unwrap(all)
+        .unwrap_or_else(|_| panic!("unable to unwrap metadata arc"))
+        .into_inner()
+        .expect("unable to unwrap metadata mutex");
+    all.sort_by(|a, b| a.title.cmp(&b.title));
+    Ok(all)
+}
+
+/// For `--metadata-only`: write the metadata of all the given docs as a JSON array to the given
+/// path.
+fn dump_metadata(
+    ids: Vec<String>,
+    client: Arc<dyn PaperSource>,
+    config: Arc<Config>,
+    path: &Path,
+) -> Result<()> {
+    let all = fetch_metadata(ids, client, config)?;
+    let file = File::create(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    serde_json::to_writer_pretty(file, &all)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// For `--list-only`: print the metadata of all the given docs to stdout as a JSON array, and
+/// nothing else.
+fn print_doc_list(ids: Vec<String>, client: Arc<dyn PaperSource>, config: Arc<Config>)
+    -> Result<()>
+{
+    let docs = fetch_metadata(ids, client, config)?
+        .into_iter()
+        .map(|doc| ListedDoc {
+            id: doc.id,
+            url: doc.url,
+            name: doc.title,
+            owner: doc.owner,
+            folder: doc.folders.join(" / "),
+            revision: doc.revision,
+        })
+        .collect::<Vec<_>>();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    serde_json::to_writer_pretty(&mut out, &docs).context("failed to write the doc list")?;
+    writeln!(out).context("failed to write the doc list")?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum DocStatus {
+    Ok,
+    Skip(SkipReason),
+    /// With the reason it failed.
+    Fail(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SkipReason {
+    /// It's already downloaded, and hasn't changed since.
+    Unchanged,
+    /// Left out by `--filter` or `--owner`.
+    Filtered,
+    /// The run is being stopped.
+    Stopped,
+}
+
+/// What happened when processing a doc.
+struct DocReport {
+    status: DocStatus,
+    /// The doc's title, if it got far enough to find out.
+    title: Option<String>,
+    /// How many images were downloaded, out of how many the doc references.
+    images: Option<(usize, usize)>,
+    /// Everything that happened, for verbose output.
+    log: String,
+}
+
+impl DocReport {
+    fn new(status: DocStatus, log: String) -> Self {
+        Self { status, title: None, images: None, log }
+    }
+
+    fn ok(log: String) -> Self {
+        Self::new(DocStatus::Ok, log)
+    }
+
+    fn skip(reason: SkipReason, log: String) -> Self {
+        Self::new(DocStatus::Skip(reason), log)
+    }
+
+    /// The reason it failed is taken to be the last thing in the log.
+    fn fail(log: String) -> Self {
+        let reason = log.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or("failed");
+        Self::new(DocStatus::Fail(reason.trim().to_owned()), log)
+    }
+
+    fn with_title(self, title: String) -> Self {
+        Self { title: Some(title), ..self }
+    }
+
+    /// A one-line summary for `--compact` output.
+    fn compact_line(&self, id: &str, n: usize, total: usize) -> String {
+        let status = match self.status {
+            DocStatus::Ok => "OK",
+            DocStatus::Skip(_) => "SKIP",
+            DocStatus::Fail(_) => "FAIL",
+        };
+        let mut line = format!("[{}/{}] {:<4}  {}", n, total, status,
+            self.title.as_deref().unwrap_or(id));
+        if let Some((ok, total)) = self.images {
+            line += &format!("  ({}/{} images)", ok, total);
+        }
+        line
+    }
+}
+
+/// Totals over all the docs in a run.
+#[derive(Default)]
+struct RunSummary {
+    docs: usize,
+    downloaded: usize,
+    unchanged: usize,
+    filtered: usize,
+    stopped: usize,
+    failed: usize,
+    images_ok: usize,
+    images_total: usize,
+}
+
+impl RunSummary {
+    fn add(&mut self, report: &DocReport) {
+        self.docs += 1;
+        match report.status {
+            DocStatus::Ok => self.downloaded += 1,
+            DocStatus::Skip(SkipReason::Unchanged) => self.unchanged += 1,
+            DocStatus::Skip(SkipReason::Filtered) => self.filtered += 1,
+            DocStatus::Skip(SkipReason::Stopped) => self.stopped += 1,
+            DocStatus::Fail(_) => self.failed += 1,
+        }
+        if let Some((ok, total)) = report.images {
+            self.images_ok += ok;
+            self.images_total += total;
+        }
+    }
+
+    fn print(&self, bytes_written: u64) {
+        eprintln!();
+        eprintln!("{:<30} {:>12}", "docs", self.docs);
+        eprintln!("{:<30} {:>12}", "downloaded", self.downloaded);
+        eprintln!("{:<30} {:>12}", "skipped (unchanged)", self.unchanged);
+        eprintln!("{:<30} {:>12}", "filtered out", self.filtered);
+        if self.stopped != 0 {
+            eprintln!("{:<30} {:>12}", "not started (run stopped)", self.stopped);
+        }
+        eprintln!("{:<30} {:>12}", "failed", self.failed);
+        eprintln!("{:<30} {:>12}", "images fetched",
+            format!("{}/{}", self.images_ok, self.images_total));
+        eprintln!("{:<30} {:>12}", "bytes written", bytes_written);
+    }
+}
+
+/// Check whether an image could be downloaded, without actually downloading it.
+fn test_image_url(url: &str, config: &Config) -> Result<(), String> {
+    image_url_filename(url)?;
+    if let Some(rate) = &config.image_rate {
+        rate.wait();
+    }
+    let response = match config.agent.head(url).call() {
+        // Not everything supports HEAD; ask for just the first byte instead.
+        Err(ureq::Error::Status(405, _)) | Err(ureq::Error::Status(501, _)) => {
+            config.agent.get(url).set("Range", "bytes=0-0").call()
+        }
+        other => other,
+    };
+    match response {
+        Ok(response) => check_image_response(url, &response),
+        Err(ureq::Error::Status(code, _)) => Err(format!("{}: HTTP {}", url, code)),
+        Err(e) => Err(format!("{}: {}", url, e)),
+    }
+}
+
+/// Download a sample of docs and check that their images are fetchable, without writing anything.
+fn test_image_urls(
+    ids: Vec<String>,
+    client: Arc<dyn PaperSource>,
+    config: Arc<Config>,
+    sample: usize,
+) -> Result<()> {
+    let pool = ThreadPool::new(config.page_threads);
+    let counts = Arc::new(Mutex::new((0usize, 0usize)));
+    for id in ids.into_iter().take(sample) {
+        let client = Arc::clone(&client);
+        let config = Arc::clone(&config);
+        let counts = Arc::clone(&counts);
+        pool.execute(move || {
+            let mut output = format!("https://paper.dropbox.com/doc/{}\n", id);
+            let result = download_doc(&id, &*client, &config, true, ExportFormat::Html, &mut output);
+            let mut had_errors = result.is_none();
+            if let Some(mut result) = result {
+                let mut html = vec![];
+                match result.body.as_mut().expect("response must have body").read_to_end(&mut html) {
+                    Ok(_) => {
+                        if config.strip_comments {
+                            html = strip_comments(&html);
+                        }
+                        let base = Url::parse(&format!("https://paper.dropbox.com/doc/{}", id))
+                            .expect("doc URL must be valid");
+                        let images = find_images(&html, &config.img_re, &base, &mut output);
+                        let mut ok = 0;
+                        for (_, _, url) in &images {
+                            match test_image_url(url, &config) {
+                                Ok(()) => ok += 1,
+                                Err(e) => output += &format!("image would fail: {}\n", e),
+                            }
+                        }
+                        output += &format!("{} of {} images OK\n", ok, images.len());
+                        had_errors |= ok < images.len();
+                        let mut counts = counts.lock().unwrap();
+                        counts.0 += ok;
+                        counts.1 += images.len();
+                    }
+                    Err(e) => {
+                        output += &format!("I/O error reading doc: {}\n", e);
+                        had_errors = true;
+                    }
+                }
+            }
+            if had_errors {
+                warn!("{}", output.trim_end());
+            } else {
+                info!("{}", output.trim_end());
+            }
+        });
+    }
+    pool.join();
+
+    let (ok, total) = *counts.lock().unwrap();
+    info!("{} of {} images in the sampled docs look fetchable", ok, total);
+    Ok(())
+}
+
+/// Set on Ctrl-C. Docs already started are finished, but no more get started, and then the doc
+/// list is saved so the next run can pick up where this one left off.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Set up Ctrl-C to stop the run gracefully, or immediately if it's pressed a second time.
+fn catch_interrupts() {
+    #[cfg(unix)]
+    {
+        extern "C" fn on_sigint(_: libc::c_int) {
+            if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                unsafe { libc::_exit(130) };
+            }
+            // Only async-signal-safe calls in here, so no eprintln.
+            let msg: &[u8] = b"\ninterrupted; finishing the docs in progress and saving the list \
+                (Ctrl-C again to quit now)\n";
+            unsafe { libc::write(2, msg.as_ptr().cast(), msg.len()) };
+        }
+        unsafe { libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t) };
+    }
+}
+
+/// Block for as long as `.pause` exists in the output directory. Docs already being worked on
+/// carry on regardless.
+fn wait_while_paused(config: &Config) {
+    let pause_file = config.out_dir.join(".pause");
+    while pause_file.exists() && !interrupted() {
+        if !config.paused.swap(true, Ordering::SeqCst) {
+            info!("{} exists; pausing until it is removed", pause_file.display());
+        }
+        thread::sleep(Duration::from_secs(5));
+    }
+    if config.paused.swap(false, Ordering::SeqCst) {
+        info!("{} removed; resuming", pause_file.display());
+    }
+}
+
+fn fetch_doc(
+    id: &str,
+    client: Arc<dyn PaperSource>,
+    config: Arc<Config>,
+    images_pool: Arc<Mutex<ThreadPool>>,
+    doc_map: Arc<Mutex<HashMap<String, DocInfo>>>,
+) -> DocReport {
+    let url = format!("https://paper.dropbox.com/doc/{}", id);
+    let export = config.export;
+
+    // buffer output until we're done, so that we don't interleave with other jobs
+    let mut output = url.clone() + "\n";
+
+    let previous = doc_map.lock().unwrap().get(&url)
+        .map(|doc| (doc.path.clone(), doc.revision, doc.content_hash.clone()));
+
+    wait_while_paused(&config);
+
+    if config.aborting.load(Ordering::SeqCst) {
+        output += "run aborted; skipping\n";
+        return DocReport::skip(SkipReason::Stopped, output);
+    }
+    if interrupted() {
+        output += "run interrupted; skipping\n";
+        return DocReport::skip(SkipReason::Stopped, output);
+    }
+
+    if let Some((old_path, revision, _)) = previous.as_ref().filter(|_| !config.force_refresh) {
+        // Only get the whole thing again if it's changed.
+        let old_path = config.out_dir.join(old_path);
+        match download_doc(id, &*client, &config, false, ExportFormat::Html, &mut output) {
+            Some(current) if Some(current.result.revision) == *revision => {
+                output += "already downloaded; skipping\n";
+                return DocReport::skip(SkipReason::Unchanged, output)
+                    .with_title(current.result.title);
+            }
+            // Lists from older versions don't have the revision. Rather than download everything
+            // again, go by the one in the file's header, or if it doesn't have one, assume it's
+            // up to date.
+            Some(current) if revision.is_none()
+                && old_path.exists()
+                && header_revision(&old_path).is_none_or(|rev| rev == current.result.revision) =>
+            {
+                if let Some(doc) = doc_map.lock().unwrap().get_mut(&url) {
+                    doc.revision = Some(current.result.revision);
+                }
+                output += "already downloaded; skipping\n";
+                return DocReport::skip(SkipReason::Unchanged, output)
+                    .with_title(current.result.title);
+            }
+            Some(_) => output += "changed since it was downloaded; downloading again\n",
+            None => return DocReport::fail(output),
+        }
+    }
+
+    let export_format = if config.format == OutputFormat::Markdown {
+        ExportFormat::Markdown
+    } else {
+        ExportFormat::Html
+    };
+    let mut export_result = match download_doc(id, &*client, &config, export, export_format,
+        &mut output)
+    {
+        Some(result) => result,
+        None => return DocReport::fail(output),
+    };
+    let title = export_result.result.title.clone();
+
+    output += &format!("title: {}\nowner: {}\n",
+        export_result.result.title,
+        export_result.result.owner);
+
+    if let Some(re) = &config.title_filter {
+        if !re.is_match(&title) {
+            output += "filtered out\n";
+            return DocReport::skip(SkipReason::Filtered, output).with_title(title);
+        }
+    }
+    if let Some(owner) = &config.owner_filter {
+        if export_result.result.owner.to_lowercase() != *owner {
+            output += "owned by someone else; filtered out\n";
+            return DocReport::skip(SkipReason::Filtered, output).with_title(title);
+        }
+    }
+
+    if !export {
+        return DocReport::ok(output).with_title(title);
+    }
+
+    let mut filename = doc_filename(&export_result.result.title, id, &config);
+
+    let folders = doc_folders(&*client, id, &mut output);
+    // How to get from the doc back up to the top, where the images are.
+    let mut up = String::new();
+    if config.nest_folders && !folders.is_empty() {
+        let mut subdir = PathBuf::new();
+        for folder in &folders {
+            subdir.push(folder_dir_name(folder));
+            up += "../";
+        }
+        if let Err(e) = fs::create_dir_all(config.out_dir.join(&subdir)) {
+            output += &format!("failed to create folder {:?}: {}\n", subdir, e);
+            return DocReport::fail(output).with_title(title);
+        }
+        filename = subdir.join(filename).to_string_lossy().replace('\\', "/");
+    }
+
+    let path = config.out_dir.join(&filename);
+
+    let mut html = vec![];
+    if let Err(e) = export_result.body.as_mut().expect("response must have body")
+        .read_to_end(&mut html)
+    {
+        output += &format!("I/O error reading doc: {}\n", e);
+        return DocReport::fail(output).with_title(title);
+    }
+    let content_hash = hash_bytes(&html);
+
+    if let Some((old_path, revision, old_hash)) = &previous {
+        // Downloaded again only because of --force-refresh.
+        if *revision == Some(export_result.result.revision)
+            && old_hash.as_deref() == Some(&content_hash)
+            && *old_path == filename
+            && path.exists()
+            && !config.force
+        {
+            output += "same content as before; keeping the file\n";
+            return DocReport::skip(SkipReason::Unchanged, output).with_title(title);
+        }
+    }
+
+    let mut options = OpenOptions::new();
+    if previous.is_some() || config.force {
+        // Replacing an old version.
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let mut file = match options.write(true).open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            output += "file already downloaded; skipping\n";
+            return DocReport::skip(SkipReason::Unchanged, output).with_title(title);
+        }
+        Err(e) => {
+            output += &format!("failed to create file {:?}: {}\n", path, e);
+            return DocReport::fail(output).with_title(title);
+        }
+    };
+
+    // If it can't be written in full, don't leave anything behind: a partial file would look
+    // like a finished one to the next run. Without it or an entry in the list, the next run starts
+    // this doc over. Whatever went wrong (a full disk, say) may clear up by the end of this run,
+    // so it gets another go then too.
+    let discard = || {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("failed to remove partial file {:?}: {}", path, e);
+        }
+        if config.keep_raw {
+            let _ = fs::remove_file(raw_path(&path, config.format));
+        }
+        doc_map.lock().unwrap().remove(&url);
+        let mut gave_up = config.gave_up.lock().unwrap();
+        if !gave_up.iter().any(|gave_up_id| gave_up_id == id) {
+            gave_up.push(id.to_owned());
+        }
+    };
+
+    if config.keep_raw {
+        let raw = raw_path(&path, config.format);
+        if let Err(e) = fs::write(&raw, &html) {
+            output += &format!("I/O error writing file {:?}: {}\n", raw, e);
+            discard();
+            return DocReport::fail(output).with_title(title);
+        }
+        config.note_written(&raw);
+    }
+
+    let doc_info = DocInfo {
+        id: id.to_owned(),
+        url: url.clone(),
+        name: export_result.result.title.clone(),
+        owner: export_result.result.owner.clone(),
+        path: filename,
+        folder: folders.join(" / "),
+        revision: Some(export_result.result.revision),
+        content_hash: Some(content_hash),
+    };
+
+    // Once it's written, it goes in the list.
+    let record = || {
+        if let Some((old_path, _, _)) = &previous {
+            // If the title changed, so did the filename.
+            if *old_path != doc_info.path {
+                let old_path = config.out_dir.join(old_path);
+                let _ = fs::remove_file(raw_path(&old_path, config.format));
+                let _ = fs::remove_file(old_path);
+            }
+        }
+        doc_map.lock().unwrap()
+            .insert(url.clone(), doc_info);
+    };
+
+    if config.format == OutputFormat::Text {
+        html = format!("{}\n\n{}", export_result.result.title,
+            text::from_html(&String::from_utf8_lossy(&html))).into_bytes();
+    }
+
+    // These are written as they are, without bothering with images.
+    if matches!(config.format, OutputFormat::Markdown | OutputFormat::Text) {
+        if let Err(e) = write_text_export(&mut file, html, config.newlines_for(config.format)) {
+            output += &format!("I/O error writing file {:?}: {}\n", path, e);
+            drop(file);
+            discard();
+            return DocReport::fail(output).with_title(title);
+        }
+        config.note_written(&path);
+        record();
+        return DocReport::ok(output).with_title(title);
+    }
+
+    if config.strip_comments {
+        html = strip_comments(&html);
+    }
+
+    let base = Url::parse(&url).expect("doc URL must be valid");
+    let mut images = find_images(&html, &config.img_re, &base, &mut output);
+    images.extend(find_css_images(&html, &config, &base, &mut output));
+
+    // Responsive images: the biggest candidate in each srcset is fetched along with the rest.
+    let srcsets = find_srcset_images(&html, &config.srcset_re, &base, &mut output);
+
+    let (tx, rx) = mpsc::channel();
+    let image_cnt = images.len() + srcsets.len();
+    let images_pool_locked = images_pool.lock().unwrap();
+    let urls = images.iter().map(|(_, _, url)| url).chain(srcsets.iter().map(|(_, _, url)| url));
+    for (i, url) in urls.enumerate() {
+        let tx = tx.clone();
+        let config = Arc::clone(&config);
+        let up = up.clone();
+        let url = url.clone();
+        images_pool_locked.execute(move || {
+            // Anything over the size cap is left for fetch_image to reject.
+            let inline_max = config.inline_images
+                .map(|max| max.min(config.max_image_bytes.unwrap_or(u64::MAX)));
+            let inlined = inline_max.map(|max| fetch_image_inline(&url, &config, max));
+            let to_file = |started| {
+                fetch_image(&url, &config, started).map(|path| (up + &path, Some(path)))
+            };
+            // What to point the tag at, and the file it's in, if any.
+            let result = match inlined {
+                Some(Inlined::Done(result)) => result.map(|data_uri| (data_uri, None)),
+                Some(Inlined::TooBig(started)) => to_file(Some(started)),
+                None => to_file(None),
+            };
+            tx.send((i, result)).expect("channel busted");
+        })
+    }
+    drop(images_pool_locked);
+
+    let mut results = (0 .. image_cnt).map(|_| None).collect::<Vec<_>>();
+    for _ in 0 .. image_cnt {
+        match rx.recv() {
+            Ok((i, Ok(fetched))) => results[i] = Some(fetched),
+            Ok((_, Err(e))) => {
+                config.log_error("image", id, &e);
+                output += &format!("failed to fetch image: {}\n", e);
+            }
+            Err(e) => {
+                output += &format!("image thread died?!?: {}\n", e);
+            }
+        }
+    }
+    let images_ok = results.iter().filter(|r| r.is_some()).count();
+    let srcset_results = results.split_off(images.len());
+
+    let mut replacements = vec![];
+    for ((Range { start, end }, original_tag, url), fetched) in images.into_iter().zip(results) {
+        if let Some((src, path)) = fetched {
+            replacements.push((start, end, original_tag.replace(&url, &src).into_bytes(), path));
+        }
+    }
+    // If the tag has a src as well, the srcset is replaced with just the one image; otherwise it
+    // becomes the src. The path kept with a replacement is always its src's, since that's the one
+    // that matters for EPUB.
+    for ((tag, attr, _), fetched) in srcsets.into_iter().zip(srcset_results) {
+        let (src, path) = match fetched {
+            Some(fetched) => fetched,
+            None => continue,
+        };
+        let old_attr = String::from_utf8_lossy(&html[attr.clone()]);
+        match replacements.iter_mut().find(|r| r.0 == tag.start) {
+            Some(existing) => {
+                existing.2 = String::from_utf8_lossy(&existing.2)
+                    .replacen(&*old_attr, &format!("srcset=\"{}\"", src), 1)
+                    .into_bytes();
+            }
+            None => {
+                // A src that wasn't downloaded (a data: URI, say) stays, and comes first.
+                let has_src = config.img_re.is_match(&html[tag.clone()]);
+                let new_attr = if has_src { "srcset" } else { "src" };
+                let mut replacement = html[tag.start .. attr.start].to_vec();
+                replacement.extend_from_slice(format!("{}=\"{}\"", new_attr, src).as_bytes());
+                replacement.extend_from_slice(&html[attr.end .. tag.end]);
+                replacements.push((tag.start, tag.end, replacement, path.filter(|_| !has_src)));
+            }
+        }
+    }
+    output += &format!("downloaded {} of {} images\n", images_ok, image_cnt);
+
+    if config.extract_data_uris {
+        let mut extracted = 0;
+        for m in config.img_re.captures_iter(&html) {
+            let uri = String::from_utf8_lossy(captured_url(&m));
+            if !uri.starts_with("data:") {
+                continue;
+            }
+            match extract_data_uri(&uri, &config) {
+                Some(Ok(path)) => {
+                    let tag = m.get(0).unwrap();
+                    let attr = m.name("attr").unwrap();
+                    let new_attr = format!("src=\"{}{}\"", up, path);
+                    extracted += 1;
+                    // The srcset may have already been replaced, in which case this goes in the
+                    // same replacement.
+                    match replacements.iter_mut().find(|r| r.0 == tag.start()) {
+                        Some(existing) => {
+                            let old_attr = String::from_utf8_lossy(&html[attr.range()]);
+                            existing.2 = String::from_utf8_lossy(&existing.2)
+                                .replacen(&*old_attr, &new_attr, 1)
+                                .into_bytes();
+                            existing.3 = Some(path);
+                        }
+                        None => {
+                            let mut replacement = html[tag.start() .. attr.start()].to_vec();
+                            replacement.extend_from_slice(new_attr.as_bytes());
+                            replacement.extend_from_slice(&html[attr.end() .. tag.end()]);
+                            replacements.push((tag.start(), tag.end(), replacement, Some(path)));
+                        }
+                    }
+                }
+                Some(Err(e)) => output += &format!("failed to save embedded image: {}\n", e),
+                None => (), // not something that can be decoded; leave it be
+            }
+        }
+        if extracted > 0 {
+            output += &format!("saved {} embedded images\n", extracted);
+        }
+    }
+    let toc = if config.toc {
+        table_of_contents(&html, &config, &mut replacements)
+    } else {
+        String::new()
+    };
+    // Markup the regexes don't expect (like an image tag inside an attribute value) can make two
+    // replacements overlap. Only the first of them can be spliced in.
+    replacements.sort_by_key(|r| (r.0, r.1));
+    let mut last_end = 0;
+    replacements.retain(|r| {
+        if r.0 < last_end {
+            warn!("{}: skipping a replacement at byte {} that overlaps another one", id, r.0);
+            return false;
+        }
+        last_end = r.1;
+        true
+    });
+
+    let date = if config.date_stamp {
+        let now = if config.utc { config.now_rfc3339() } else { chrono::Local::now().to_rfc2822() };
+        format!(" on {}", now)
+    } else {
+        String::new()
+    };
+    let header = format!("<p>\
+            downloaded rev {rev}{date} from <a href=\"{url}\">{url}</a><br>
+            owned by {owner}</p>\n",
+        owner=export_result.result.owner,
+        rev=export_result.result.revision,
+        url=url,
+        date=date);
+
+    // Write the doc with the images spliced in, in pieces, to avoid holding another whole copy
+    // of it in memory.
+    let write_body = |out: &mut dyn Write| -> io::Result<()> {
+        out.write_all(header.as_bytes())?;
+        out.write_all(toc.as_bytes())?;
+        let mut last_end = 0;
+        for (start, end, replacement, _) in &replacements {
+            out.write_all(&html[last_end .. *start])?;
+            out.write_all(replacement)?;
+            last_end = *end;
+        }
+        out.write_all(&html[last_end ..])
+    };
+
+    let write_result = match config.format {
+        OutputFormat::Html | OutputFormat::Both => {
+            // Compressing needs the whole thing at once.
+            let mut uncompressed = vec![];
+            let inner: Box<dyn Write + '_> = if config.gzip {
+                Box::new(&mut uncompressed)
+            } else {
+                Box::new(io::BufWriter::new(&mut file))
+            };
+            let mut out = NewlineWriter::new(inner, config.newlines_for(config.format));
+            let style = if config.embed_css { EMBEDDED_CSS } else { "" };
+            let write_html = |out: &mut dyn Write| -> io::Result<()> {
+                write!(out, "<!DOCTYPE html><html><head><title>{}</title>{}</head><body>",
+                    export_result.result.title, style)?;
+                write_body(out)?;
+                out.write_all(b"</body></html>\n")
+            };
+            let result = if config.minify {
+                // This needs the whole thing too, with the images already spliced in.
+                let mut doc = vec![];
+                write_html(&mut doc).unwrap();
+                let doc = match minify::minify(&doc) {
+                    Ok(minified) => minified,
+                    Err(e) => {
+                        output += &format!("failed to minify: {}; writing it as it is\n", e);
+                        doc
+                    }
+                };
+                out.write_all(&doc)
+            } else {
+                write_html(&mut out)
+            };
+            let result = result
+                .and_then(|()| out.finish())
+                .map(|_| ());
+            if config.gzip {
+                result.and_then(|()| file.write_all(&gzip::compress(&uncompressed)))
+            } else {
+                result
+            }
+        }
+        OutputFormat::Epub => {
+            let mut body = vec![];
+            write_body(&mut body).unwrap();
+            let image_paths = replacements.iter().filter_map(|r| r.3.clone());
+            let mut images = vec![];
+            for image_path in image_paths {
+                match fs::read(config.out_dir.join(&image_path)) {
+                    Ok(data) => images.push((up.clone() + &image_path, data)),
+                    Err(e) => output += &format!("failed to read image {}: {}\n", image_path, e),
+                }
+            }
+            let body = String::from_utf8_lossy(&body);
+            let doc = epub::EpubDoc {
+                identifier: &url,
+                title: &export_result.result.title,
+                author: &export_result.result.owner,
+                body: &body,
+                images,
+                modified: if config.date_stamp { Some(chrono::Utc::now()) } else { None },
+                utc: config.utc,
+            };
+            epub::write(&mut file, &doc).map(|_| ())
+        }
+        OutputFormat::Markdown | OutputFormat::Text => unreachable!("written as-is above"),
+    };
+
+    if let Err(e) = write_result {
+        output += &format!("I/O error writing file {:?}: {}\n", path, e);
+        drop(file);
+        discard();
+        return DocReport::fail(output).with_title(title);
+    }
+    config.note_written(&path);
+
+    if config.format == OutputFormat::Both {
+        let md_path = path.with_extension("md");
+        let mut markdown = vec![];
+        let result = download_doc(id, &*client, &config, true, ExportFormat::Markdown, &mut output)
+            .ok_or_else(|| io::Error::other("download failed"))
+            .and_then(|mut result| {
+                result.body.as_mut().expect("response must have body").read_to_end(&mut markdown)
+            })
+            .and_then(|_| File::create(&md_path))
+            .and_then(|mut file| {
+                write_text_export(&mut file, markdown, config.newlines_for(OutputFormat::Markdown))
+            });
+        if let Err(e) = result {
+            output += &format!("failed to write Markdown file {:?}: {}\n", md_path, e);
+            // Half of it is no good either: the next run needs to do both again.
+            drop(file);
+            let _ = fs::remove_file(&md_path);
+            discard();
+            return DocReport::fail(output).with_title(title);
+        }
+        config.note_written(&md_path);
+    }
+    record();
+
+    DocReport {
+        images: Some((images_ok, image_cnt)),
+        ..DocReport::ok(output).with_title(title)
+    }
+}
+
+/// The revision given in a downloaded doc's header, if it has one that can be read.
+fn header_revision(path: &Path) -> Option<i64> {
+    static REV_RE: std::sync::OnceLock<Regex> = std::sync::OnceLock::new();
+    let rev_re = REV_RE.get_or_init(|| Regex::new(r"<p>downloaded rev (\d+)").unwrap());
+    // It's near the top, after the title.
+    let mut start = vec![];
+    File::open(path).ok()?.take(16 * 1024).read_to_end(&mut start).ok()?;
+    let c = rev_re.captures(&start)?;
+    std::str::from_utf8(&c[1]).ok()?.parse().ok()
+}
+
+/// Remove `<!-- ... -->` comments, except for conditional comments, which do something.
+fn strip_comments(html: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = find_bytes(rest, b"<!--") {
+        let end = match find_bytes(&rest[start + 4 ..], b"-->") {
+            Some(end) => start + 4 + end + 3,
+            None => break, // unterminated; leave it alone
+        };
+        out.extend_from_slice(&rest[.. start]);
+        if rest[start ..].starts_with(b"<!--[if") || rest[start ..].starts_with(b"<!--<![endif]") {
+            out.extend_from_slice(&rest[start .. end]);
+        }
+        rest = &rest[end ..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Write out a Markdown or plain text export, with the given line endings.
+fn write_text_export(file: &mut File, text: Vec<u8>, newlines: Newlines) -> io::Result<()> {
+    file.write_all(&newlines.apply(text))
+}
+
+/// Find all the images in a doc which need downloading. Returns the range of each tag, the tag
+/// text with its `src` double-quoted and made absolute (so that any URL can be put in its place),
+/// and the image URL. Relative and protocol-relative URLs are resolved against `base`, the doc's
+/// URL.
+fn find_images(html: &[u8], img_re: &Regex, base: &Url, output: &mut String)
+    -> Vec<(Range<usize>, String, String)>
+{
+    let mut images = vec![];
+    for m in img_re.captures_iter(html) {
+        let original_tag = match std::str::from_utf8(m.get(0).unwrap().as_bytes()) {
+            Ok(s) => s.to_owned(),
+            Err(e) => {
+                *output += &format!("non-UTF8 image tag {:?}: {}\n", &m, e);
+                continue;
+            }
+        };
+        let url = std::str::from_utf8(captured_url(&m)).unwrap();
+        if url.starts_with("data:") {
+            continue;
+        }
+        let url = match base.join(url) {
+            Ok(url) => String::from(url),
+            Err(e) => {
+                *output += &format!("bad image URL {:?}: {}\n", url, e);
+                continue;
+            }
+        };
+        let tag_range = m.get(0).unwrap().range();
+        let attr = m.name("attr").unwrap().range();
+        let tag = format!("{}src=\"{}\"{}",
+            &original_tag[.. attr.start - tag_range.start],
+            url,
+            &original_tag[attr.end - tag_range.start ..]);
+        images.push((tag_range, tag, url));
+    }
+    images
+}
+
+/// Find the image tags with a `srcset`, returning the range of each tag and of its `srcset`
+/// attribute, and the absolute URL of its biggest candidate. Candidates that are `data:` URIs are
+/// left alone.
+fn find_srcset_images(html: &[u8], srcset_re: &Regex, base: &Url, output: &mut String)
+    -> Vec<(Range<usize>, Range<usize>, String)>
+{
+    let mut images = vec![];
+    for m in srcset_re.captures_iter(html) {
+        let srcset = String::from_utf8_lossy(captured_url(&m));
+        let candidate = match largest_srcset_candidate(&srcset) {
+            Some(candidate) if !candidate.starts_with("data:") => candidate,
+            _ => continue,
+        };
+        match base.join(candidate) {
+            Ok(url) => {
+                images.push((m.get(0).unwrap().range(), m.name("attr").unwrap().range(),
+                    String::from(url)));
+            }
+            Err(e) => *output += &format!("bad image URL {:?}: {}\n", candidate, e),
+        }
+    }
+    images
+}
+
+/// Pick the URL of the highest-resolution candidate from a `srcset` value: the one with the largest
+/// width (`640w`) or pixel density (`2x`) descriptor, with no descriptor counting as `1x`.
+fn largest_srcset_candidate(srcset: &str) -> Option<&str> {
+    let mut best: Option<(&str, f64)> = None;
+    for (range, descriptor) in srcset_candidates(srcset) {
+        let url = &srcset[range];
+        let size = match descriptor.char_indices().last() {
+            None => Some(1.),
+            Some((i, 'w')) | Some((i, 'x')) => descriptor[.. i].parse::<f64>().ok(),
+            Some(_) => None,
+        };
+        if let Some(size) = size {
+            if best.is_none_or(|(_, best_size)| size > best_size) {
+                best = Some((url, size));
+            }
+        }
+    }
+    best.map(|(url, _)| url)
+}
+
+/// Split a `srcset` value into its candidates: where each URL is in the value, and its descriptor,
+/// which is empty if there isn't one.
+fn srcset_candidates(srcset: &str) -> Vec<(Range<usize>, &str)> {
+    let mut candidates = vec![];
+    let mut pos = 0;
+    loop {
+        pos += srcset[pos ..].len()
+            - srcset[pos ..].trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',').len();
+        if pos == srcset.len() {
+            break;
+        }
+        // The URL runs up to whitespace; a comma on the end of it means there's no descriptor.
+        let url_end = srcset[pos ..].find(|c: char| c.is_ascii_whitespace())
+            .map_or(srcset.len(), |end| pos + end);
+        let url = srcset[pos .. url_end].trim_end_matches(',');
+        let range = pos .. pos + url.len();
+        pos = url_end;
+        let descriptor = if url.len() < url_end - range.start {
+            ""
+        } else {
+            let end = srcset[pos ..].find(',').map_or(srcset.len(), |end| pos + end);
+            let descriptor = srcset[pos .. end].trim();
+            pos = end;
+            descriptor
+        };
+        candidates.push((range, descriptor));
+    }
+    candidates
+}
+
+/// Save an image given as a base64 `data:` URI to a file named by its content, returning the
+/// file's path. Returns `None` for URIs that aren't base64 images of a known type.
+fn extract_data_uri(uri: &str, config: &Config) -> Option<Result<String, String>> {
+    let (meta, data) = uri.strip_prefix("data:")?.split_once(',')?;
+    let mut params = meta.split(';');
+    let ext = image_type_extension(params.next()?.trim())?;
+    if !params.any(|p| p.trim().eq_ignore_ascii_case("base64")) {
+        return None;
+    }
+    let data = data.chars().filter(|c| !c.is_ascii_whitespace()).collect::<String>();
+    let data = base64::decode(data).ok()?;
+    let hash = hash_bytes(&data);
+    let path = format!("images/{}.{}", hash, ext);
+    let local = config.out_dir.join(&path);
+    if !local.exists() {
+        if let Err(e) = write_atomically(&local, |f| f.write_all(&data)) {
+            return Some(Err(format!("failed to write {}: {}", path, e)));
+        }
+        config.note_written(&local);
+    }
+    Some(Ok(path))
+}
+
+/// Where `--keep-raw` puts the doc at `path` as it was exported, which is HTML unless the output
+/// format is Markdown.
+fn raw_path(path: &Path, format: OutputFormat) -> PathBuf {
+    // NAME.html.gz has NAME.raw.html.
+    let path = match path.extension() {
+        Some(ext) if ext == "gz" => path.with_extension(""),
+        _ => path.to_owned(),
+    };
+    path.with_extension(if format == OutputFormat::Markdown { "raw.md" } else { "raw.html" })
+}
+
+/// Find images used in `style` attributes, like `background-image: url(...)`. Returns the same as
+/// [`find_images`], except that what gets replaced is just the `url(...)`. Image tags are left to
+/// `find_images`, so the two don't overlap.
+fn find_css_images(html: &[u8], config: &Config, base: &Url, output: &mut String)
+    -> Vec<(Range<usize>, String, String)>
+{
+    let mut images = vec![];
+    for tag in config.style_re.captures_iter(html) {
+        if tag["name"].eq_ignore_ascii_case(b"img") {
+            continue;
+        }
+        let (style, quote) = match (tag.name("style"), tag.name("style_sq")) {
+            (Some(style), _) => (style, '\''), // inside "...", so quote the new URL with '
+            (None, Some(style)) => (style, '"'),
+            (None, None) => continue,
+        };
+        for m in config.css_url_re.captures_iter(style.as_bytes()) {
+            let found = ["url", "url_dq", "url_sq", "url_ent"].iter()
+                .find_map(|name| m.name(name))
+                .unwrap();
+            let url = match std::str::from_utf8(found.as_bytes()) {
+                Ok(url) => url.trim(),
+                Err(e) => {
+                    *output += &format!("non-UTF8 image URL in style {:?}: {}\n", found, e);
+                    continue;
+                }
+            };
+            if url.is_empty() || url.starts_with("data:") {
+                continue;
+            }
+            let url = match base.join(url) {
+                Ok(url) => String::from(url),
+                Err(e) => {
+                    *output += &format!("bad image URL {:?}: {}\n", url, e);
+                    continue;
+                }
+            };
+            let range = m.get(0).unwrap().range();
+            let range = style.start() + range.start .. style.start() + range.end;
+            images.push((range, format!("url({q}{}{q})", url, q = quote), url));
+        }
+    }
+    images
+}
+
+/// Build a table of contents from a doc's `<h1>` to `<h3>` headings, as nested lists of links to
+/// them. Headings without an id get one, added to `replacements` so it's spliced in along with the
+/// images. Returns an empty string if there are no headings.
+fn table_of_contents(
+    html: &[u8],
+    config: &Config,
+    replacements: &mut Vec<(usize, usize, Vec<u8>, Option<String>)>,
+) -> String {
+    static ID_RE: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let id_re = ID_RE.get_or_init(|| {
+        regex::Regex::new(r#"(?i)\sid\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap()
+    });
+    let headings = config.heading_re.captures_iter(html)
+        .map(|c| {
+            let attrs = String::from_utf8_lossy(&c["attrs"]);
+            let id = id_re.captures(&attrs)
+                .map(|id| id.get(1).or_else(|| id.get(2)).unwrap().as_str().to_owned());
+            let level = (c["level"][0] - b'0') as usize;
+            let text = text::inline_text(&String::from_utf8_lossy(&c["text"]));
+            (level, c.name("attrs").unwrap().start(), id, text)
+        })
+        .collect::<Vec<_>>();
+    let min_level = match headings.iter().map(|h| h.0).min() {
+        Some(level) => level,
+        None => return String::new(),
+    };
+
+    let mut ids = headings.iter().filter_map(|h| h.2.clone()).collect::<HashSet<_>>();
+    let mut toc = String::from("<nav class=\"toc\">");
+    let mut open = 0; // how many lists deep we are
+    for (level, attrs_start, id, text) in headings {
+        let id = match id {
+            Some(id) => id,
+            None => {
+                let mut slug = text.to_lowercase()
+                    .split(|c: char| !c.is_ascii_alphanumeric())
+                    .filter(|word| !word.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("-");
+                if slug.is_empty() {
+                    slug += "section";
+                }
+                let mut id = slug.clone();
+                let mut n = 1;
+                while ids.contains(&id) {
+                    n += 1;
+                    id = format!("{}-{}", slug, n);
+                }
+                ids.insert(id.clone());
+                // Right after the tag name, so it can't overlap anything else being replaced.
+                replacements.push((attrs_start, attrs_start, format!(" id=\"{}\"", id).into_bytes(),
+                    None));
+                id
+            }
+        };
+
+        // Sub-headings go in a list inside the item for the heading they're under. If a level
+        // gets skipped, there's an empty item to hold the list.
+        let level = level - min_level + 1;
+        if level > open {
+            let was_open = open;
+            while open < level {
+                open += 1;
+                if open > 1 && open != was_open + 1 {
+                    toc += "<li>";
+                }
+                toc += "<ul>";
+            }
+        } else {
+            toc += "</li>";
+            while open > level {
+                toc += "</ul></li>";
+                open -= 1;
+            }
+        }
+        toc += &format!("<li><a href=\"#{}\">{}</a>", id, epub::escape(&text));
+    }
+    toc += "</li>";
+    while open > 1 {
+        toc += "</ul></li>";
+        open -= 1;
+    }
+    toc += "</ul></nav>\n";
+    toc
+}
+
+/// Strip a string down to something usable as (part of) a filename.
+/// Non-ASCII characters are kept; only the ones that some filesystem won't allow are replaced.
+/// Trailing dots and spaces are removed too, since Windows drops them, and names Windows reserves
+/// for devices get an underscore in front.
+fn sanitize_filename(s: &str) -> String {
+    let name = s.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .trim_end_matches(['.', ' '])
+        .to_owned();
+    if is_reserved_name(&name) {
+        format!("_{}", name)
+    } else {
+        name
+    }
+}
+
+/// Whether Windows would take a file with this name to mean a device, like `CON` or `lpt1.txt`.
+fn is_reserved_name(name: &str) -> bool {
+    let base = name.split('.').next().unwrap().trim_end().to_ascii_uppercase();
+    match base.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            let prefix = base.get(.. 3);
+            let digit = base.get(3 ..).unwrap_or("");
+            (prefix == Some("COM") || prefix == Some("LPT"))
+                && digit.len() == 1
+                && matches!(digit.as_bytes()[0], b'1' ..= b'9')
+        }
+    }
+}
+
+/// How much of a doc's title (in bytes) goes into its filename. Most filesystems allow 255 bytes
+/// per name, and the ID and extension take up to about 50 more.
+const MAX_TITLE_BYTES: usize = 200;
+
+/// The name of the file a doc with the given title and ID gets written to.
+fn doc_filename(title: &str, id: &str, config: &Config) -> String {
+    let mut filename = sanitize_filename(title);
+    if filename.is_empty() {
+        filename += "(unprintable)";
+    }
+    if !config.prefix.is_empty() {
+        filename = sanitize_filename(&(config.prefix.clone() + &filename));
+    }
+    // Leave room for the rest, so that the ID always makes it in.
+    filename = truncate_utf8(&filename, MAX_TITLE_BYTES)
+        .trim_end_matches(['.', ' '])
+        .to_owned();
+    filename += &format!(" ({}).{}", id, config.format.extension());
+    if config.gzip {
+        filename += ".gz";
+    }
+    filename
+}
+
+/// The name of the directory a Paper folder gets, for `--nest-folders`.
+fn folder_dir_name(folder: &str) -> String {
+    let name = sanitize_filename(folder);
+    let name = truncate_utf8(&name, MAX_TITLE_BYTES).trim_end_matches(['.', ' ']);
+    if name.is_empty() {
+        "(unprintable)".to_owned()
+    } else if name.chars().all(|c| c == '.') {
+        // Don't let "." or ".." go anywhere unexpected.
+        name.replace('.', "_")
+    } else {
+        name.to_owned()
+    }
+}
+
+/// The longest prefix of `s` that's at most `max` bytes, without splitting a character.
+fn truncate_utf8(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[.. end]
+}
+
+fn hash_bytes(data: &[u8]) -> String {
+    use ring::digest::{digest, SHA256};
+    let hash = digest(&SHA256, data);
+    base64::encode_config(hash, base64::URL_SAFE_NO_PAD)
+}
+
+/// The last path segment of an image URL, which its local filename is based on.
+fn image_url_filename(url: &str) -> Result<String, String> {
+    Ok(Url::parse(url)
+        .map_err(|e| format!("invalid url {}: {}", url, e))?
+        .path_segments()
+        .ok_or_else(|| format!("url has no path?! {}", url))?
+        .next_back().unwrap()
+        .to_owned())
+}
+
+/// File extensions for image content types, for naming images whose URL doesn't have one.
+const IMAGE_TYPES: &[(&str, &str)] = &[
+    ("image/png", "png"), ("image/jpeg", "jpg"), ("image/gif", "gif"), ("image/webp", "webp"),
+    ("image/svg+xml", "svg"), ("image/bmp", "bmp"), ("image/tiff", "tiff"),
+    ("image/x-icon", "ico"), ("image/avif", "avif"),
+];
+
+fn image_type_extension(content_type: &str) -> Option<&'static str> {
+    IMAGE_TYPES.iter()
+        .find(|(ct, _)| ct.eq_ignore_ascii_case(content_type))
+        .map(|(_, ext)| *ext)
+}
+
+/// `name` with each of the known image extensions added, for finding an image that was given an
+/// extension from its content type.
+fn with_image_extensions(name: &str) -> impl Iterator<Item = String> + '_ {
+    IMAGE_TYPES.iter().map(move |(_, ext)| format!("{}.{}", name, ext))
+}
+
+/// Make sure the server is actually giving us an image.
+fn check_image_response(url: &str, response: &ureq::Response) -> Result<(), String> {
+    let ct = response.header("content-type").unwrap_or("");
+    if !ct.starts_with("image/") {
+        return Err(format!("{}: content type is {:?}", url, ct));
+    }
+    Ok(())
+}
+
+/// Download an image, returning its path relative to the output directory.
+fn fetch_image(url: &str, config: &Config, started: Option<StartedImage>)
+    -> Result<String, String>
+{
+    let dedupe = match &config.dedupe_content {
+        Some(dedupe) => dedupe,
+        None => {
+            let (path, fresh) = fetch_image_by_url(url, config, started)?;
+            if fresh {
+                config.note_written(&config.out_dir.join(&path));
+            }
+            return Ok(path);
+        }
+    };
+    // Other threads wanting the same image wait for the first one to get it.
+    let cell = Arc::clone(dedupe.lock().unwrap().entry(url.to_owned()).or_default());
+    cell.get_or_init(|| {
+        let (path, fresh) = fetch_image_by_url(url, config, started)?;
+        if !fresh {
+            // Something from an earlier run may be using this name.
+            return Ok(path);
+        }
+        let local = config.out_dir.join(&path);
+        let data = fs::read(&local).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        let content_hash = hash_bytes(&data);
+        let ext = Path::new(&path).extension()
+            .map(|ext| format!(".{}", ext.to_string_lossy()))
+            .unwrap_or_default();
+        let content_path = format!("images/{}{}", content_hash, ext);
+        let content_local = config.out_dir.join(&content_path);
+        let result = if content_local.exists() {
+            fs::remove_file(&local)
+        } else {
+            fs::rename(&local, &content_local)
+                .map(|()| config.note_written(&content_local))
+        };
+        result.map_err(|e| format!("failed to rename {} to {}: {}", path, content_path, e))?;
+        Ok(content_path)
+    }).clone()
+}
+
+/// Download an image to a file named after its URL. If the URL doesn't have an extension, one is
+/// added based on the content type. Also returns whether it was downloaded (or copied from the
+/// image cache) just now, as opposed to already being there.
+fn fetch_image_by_url(url: &str, config: &Config, started: Option<StartedImage>)
+    -> Result<(String, bool), String>
+{
+    let filename = image_url_filename(url)?;
+
+    let hash = hash_bytes(url.as_bytes());
+
+    let parts = filename.rsplitn(2, '.').collect::<Vec<_>>();
+    let has_ext = parts.len() == 2;
+    let mut filename = if has_ext {
+        format!("{} __{}.{}", parts[1], hash, parts[0])
+    } else {
+        format!("{} __{}", parts[0], hash)
+    };
+
+    let (path, local, file) = loop {
+        let path = format!("images/{}", filename);
+        let local = config.out_dir.join(&path);
+        if config.force && config.refreshed_images.lock().unwrap().insert(path.clone()) {
+            // Get rid of the old copy, under whatever extension it was given.
+            for old in std::iter::once(path.clone()).chain(with_image_extensions(&path)) {
+                let _ = fs::remove_file(config.out_dir.join(old));
+            }
+        }
+        if !has_ext {
+            if let Some(existing) = with_image_extensions(&path)
+                .find(|p| config.out_dir.join(p).exists())
+            {
+                return Ok((existing, false));
+            }
+        }
+        match OpenOptions::new().create_new(true).write(true)
+            .open(&local)
+        {
+            Ok(f) => break (path, local, f),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Ok((path, false));
+            }
+            Err(e) => {
+                #[cfg(unix)]
+                if e.raw_os_error() == Some(libc::ENAMETOOLONG) && filename != hash {
+                    filename = hash.clone();
+                    continue;
+                }
+                return Err(format!("failed to create file {}: {}", path, e));
+            }
+        }
+    };
+
+    let cached = config.image_cache.as_ref().filter(|_| !config.force).and_then(|dir| {
+        let mut names = vec![hash.clone()];
+        if !has_ext {
+            names.extend(with_image_extensions(&hash));
+        }
+        names.into_iter().map(|name| dir.join(name)).find(|p| p.exists())
+    });
+    if let Some(cached) = cached {
+        drop(file);
+        let (path, local) = match cached.extension() {
+            Some(ext) => {
+                let _ = fs::remove_file(&local);
+                let path = format!("{}.{}", path, ext.to_string_lossy());
+                let local = config.out_dir.join(&path);
+                (path, local)
+            }
+            None => (path, local),
+        };
+        return match link_or_copy(&cached, &local) {
+            Ok(()) => Ok((path, true)),
+            Err(e) => {
+                let _ = fs::remove_file(&local);
+                Err(format!("failed to copy {} from image cache: {}", url, e))
+            }
+        };
+    }
+
+    /// Returns the extension for the image's content type if it's a known one. On error, also
+    /// returns what kind of error it was, if it's worth retrying. `resumable` says whether the
+    /// server takes range requests, so that a retry can pick up where the last attempt left off.
+    /// The first attempt uses the `started` response, if there is one.
+    fn inner(
+        file: &mut File,
+        url: &str,
+        config: &Config,
+        resumable: &mut bool,
+        started: &mut Option<StartedImage>,
+    ) -> Result<Option<&'static str>, (String, Option<String>)> {
+        let io_err = |e: io::Error| (format!("failed to write image {}: {}", url, e), None);
+        let offset = if *resumable { file.metadata().map_err(io_err)?.len() } else { 0 };
+        if offset == 0 {
+            file.set_len(0).map_err(io_err)?;
+        }
+        file.seek(SeekFrom::Start(offset)).map_err(io_err)?;
+
+        if let Some(started) = started.take() {
+            *resumable = started.resumable;
+            return copy_image_body(file, url, config, started.body, started.len, started.ext);
+        }
+
+        if let Some(rate) = &config.image_rate {
+            rate.wait();
+        }
+        if let (Some(metrics), Ok(parsed)) = (&config.connection_metrics, Url::parse(url)) {
+            metrics.request(&parsed);
+        }
+        let mut request = config.agent.get(url);
+        if offset > 0 {
+            request = request.set("Range", &format!("bytes={}-", offset));
+        }
+        let (body, expected_len, ext) = match request.call() {
+            Ok(response) => {
+                check_image_response(url, &response).map_err(|e| (e, None))?;
+                let ext = image_type_extension(response.content_type());
+                let len = response.header("content-length").and_then(|n| n.parse::<u64>().ok());
+                let expected_len = if offset > 0 && response.status() == 206 {
+                    len.map(|n| n + offset)
+                } else {
+                    // Either a fresh start, or the server ignored the range; start over.
+                    if offset > 0 {
+                        file.set_len(0).map_err(io_err)?;
+                        file.seek(SeekFrom::Start(0)).map_err(io_err)?;
+                    }
+                    *resumable = response.header("accept-ranges") == Some("bytes");
+                    len
+                };
+                (response.into_reader(), expected_len, ext)
+            }
+            Err(ureq::Error::Status(code, _)) => {
+                let retry = code == 429 || code >= 500;
+                return Err((format!("failed to fetch {}: HTTP {}", url, code),
+                    Some(format!("http-{}", code)).filter(|_| retry)));
+            }
+            Err(e) => {
+                return Err((format!("failed to fetch {}: {}", url, e), Some("transport".to_owned())));
+            }
+        };
+        copy_image_body(file, url, config, Box::new(body), expected_len, ext)
+    }
+
+    /// Write an image's body to the file, which is already at the right offset, checking that it
+    /// comes to `expected_len` bytes, if that's known, without going over the size cap.
+    fn copy_image_body(
+        file: &mut File,
+        url: &str,
+        config: &Config,
+        body: Box<dyn Read + Send>,
+        expected_len: Option<u64>,
+        ext: Option<&'static str>,
+    ) -> Result<Option<&'static str>, (String, Option<String>)> {
+        let io_err = |e: io::Error| (format!("failed to write image {}: {}", url, e), None);
+        let max = config.max_image_bytes.unwrap_or(u64::MAX);
+        let too_big = || (format!("skipping {}: image is over {} bytes", url, max), None);
+        if expected_len.is_some_and(|len| len > max) {
+            return Err(too_big());
+        }
+        // The server may have ignored the range request, so check where the file is really at.
+        let start = file.stream_position().map_err(io_err)?;
+        let mut body = SizeLimitedReader::new(body, max.saturating_sub(start));
+        if let Err(e) = io::copy(&mut body, file) {
+            if body.exceeded {
+                return Err(too_big());
+            }
+            return Err((format!("failed to download {}: {}", url, e), Some("io".to_owned())));
+        }
+
+        if let Some(expected) = expected_len {
+            let actual = file.metadata().map_err(io_err)?.len();
+            if actual != expected {
+                return Err((format!("failed to download {}: got {} of {} bytes", url, actual, expected),
+                    Some("short-read".to_owned())));
+            }
+        }
+        Ok(ext)
+    }
+
+    let mut file = file;
+    let mut failures = 0;
+    let mut resumable = false;
+    let mut started = started;
+    let result = loop {
+        match inner(&mut file, url, config, &mut resumable, &mut started) {
+            Ok(ext) => break Ok(ext),
+            Err((msg, Some(category))) => {
+                failures += 1;
+                config.log_retry("image", url, failures, &category);
+                if failures > config.max_retries || !config.retry_budget.take() {
+                    break Err(msg);
+                }
+                thread::sleep(backoff(failures));
+            }
+            Err((msg, None)) => break Err(msg),
+        }
+    };
+
+    drop(file);
+    let ext = match result {
+        Ok(ext) => ext.filter(|_| !has_ext),
+        Err(e) => {
+            let _ = fs::remove_file(&local);
+            return Err(e);
+        }
+    };
+    let (path, local, cache_name) = match ext {
+        Some(ext) => {
+            let new_path = format!("{}.{}", path, ext);
+            let new_local = config.out_dir.join(&new_path);
+            if let Err(e) = fs::rename(&local, &new_local) {
+                let _ = fs::remove_file(&local);
+                return Err(format!("failed to rename {} to {}: {}", path, new_path, e));
+            }
+            (new_path, new_local, format!("{}.{}", hash, ext))
+        }
+        None => (path, local, hash),
+    };
+
+    if let Some(dir) = &config.image_cache {
+        // Copy to a temporary name first so other runs never see a partial image.
+        let cached = dir.join(cache_name);
+        let tmp = cached.with_extension(format!("tmp{}", std::process::id()));
+        if let Err(e) = link_or_copy(&local, &tmp).and_then(|()| fs::rename(&tmp, &cached)) {
+            warn!("failed to add {} to image cache: {}", url, e);
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    Ok((path, true))
+}
+
+/// What [`fetch_image_inline`] made of an image.
+enum Inlined {
+    /// The `data:` URI, or why the image couldn't be fetched.
+    Done(Result<String, String>),
+    /// Too big to inline; it should be downloaded to a file instead, carrying on from here.
+    TooBig(StartedImage),
+}
+
+/// An image response that's been partly read already, to be finished off by [`fetch_image`]
+/// rather than requesting the image all over again.
+struct StartedImage {
+    /// What's been read of the body, followed by the rest of it.
+    body: Box<dyn Read + Send>,
+    /// The Content-Length, if there was one.
+    len: Option<u64>,
+    ext: Option<&'static str>,
+    /// Whether the server takes range requests.
+    resumable: bool,
+}
+
+/// Download an image into a `data:` URI, if it's no bigger than `max` bytes.
+fn fetch_image_inline(url: &str, config: &Config, max: u64) -> Inlined {
+    let mut failures = 0;
+    loop {
+        if let Some(rate) = &config.image_rate {
+            rate.wait();
+        }
+        if let (Some(metrics), Ok(parsed)) = (&config.connection_metrics, Url::parse(url)) {
+            metrics.request(&parsed);
+        }
+        let (msg, category) = match config.agent.get(url).call() {
+            Ok(response) => {
+                if let Err(e) = check_image_response(url, &response) {
+                    return Inlined::Done(Err(e));
+                }
+                let len = response.header("content-length").and_then(|n| n.parse::<u64>().ok());
+                let content_type = response.content_type().to_owned();
+                let mut started = StartedImage {
+                    body: Box::new(io::empty()),
+                    len,
+                    ext: image_type_extension(&content_type),
+                    resumable: response.header("accept-ranges") == Some("bytes"),
+                };
+                let mut body = response.into_reader();
+                if len.is_some_and(|len| len > max) {
+                    started.body = Box::new(body);
+                    return Inlined::TooBig(started);
+                }
+                let mut data = vec![];
+                match body.by_ref().take(max + 1).read_to_end(&mut data) {
+                    Ok(_) if data.len() as u64 > max => {
+                        started.body = Box::new(io::Cursor::new(data).chain(body));
+                        return Inlined::TooBig(started);
+                    }
+                    Ok(_) => {
+                        return Inlined::Done(Ok(format!("data:{};base64,{}", content_type,
+                            base64::encode(&data))));
+                    }
+                    Err(e) => (format!("failed to download {}: {}", url, e), "io".to_owned()),
+                }
+            }
+            Err(ureq::Error::Status(code, _)) if code == 429 || code >= 500 => {
+                (format!("failed to fetch {}: HTTP {}", url, code), format!("http-{}", code))
+            }
+            Err(ureq::Error::Status(code, _)) => {
+                return Inlined::Done(Err(format!("failed to fetch {}: HTTP {}", url, code)));
+            }
+            Err(e) => (format!("failed to fetch {}: {}", url, e), "transport".to_owned()),
+        };
+        failures += 1;
+        config.log_retry("image", url, failures, &category);
+        if failures > config.max_retries || !config.retry_budget.take() {
+            return Inlined::Done(Err(msg));
+        }
+        thread::sleep(backoff(failures));
+    }
+}
+
+/// Hard-link `src` to `dest`, or copy it if they're on different filesystems (or links aren't
+/// supported). `dest` is replaced if it exists.
+fn link_or_copy(src: &Path, dest: &Path) -> io::Result<()> {
+    match fs::remove_file(dest) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> Config {
+        Config::new().unwrap()
+    }
+
+    /// A fresh, empty directory for a test to use, named after it.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("legacy-paper-dump-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn prefix_relative_refs_rebases_everything_local() {
+        let config = test_config();
+        let link_re = Regex::new(LINK_RE).unwrap();
+        let html = concat!(
+            r#"<p><a href="https://paper.dropbox.com/doc/abc">Paper</a> "#,
+            r##"<a href="Other%20doc%20(def).html#h">other</a> <a href="#top">top</a></p>"##,
+            r#"<img src="images/a.png" srcset="images/a.png 1x, images/b.png 2x">"#,
+            r#"<img src='data:image/png;base64,AAAA'>"#,
+            r#"<div style="background: url(images/c.png), url('https://example.com/d.png')">"#,
+            r#"<img src="/abs.png"></div>"#);
+        let out = prefix_relative_refs(html.as_bytes(), &config, &link_re, b"../");
+        assert_eq!(String::from_utf8(out).unwrap(), concat!(
+            r#"<p><a href="https://paper.dropbox.com/doc/abc">Paper</a> "#,
+            r##"<a href="../Other%20doc%20(def).html#h">other</a> <a href="#top">top</a></p>"##,
+            r#"<img src="../images/a.png" srcset="../images/a.png 1x, ../images/b.png 2x">"#,
+            r#"<img src='data:image/png;base64,AAAA'>"#,
+            r#"<div style="background: url(../images/c.png), url('https://example.com/d.png')">"#,
+            r#"<img src="/abs.png"></div>"#));
+    }
+
+    #[test]
+    fn explain_list_error_recognizes_deprecation() {
+        let gone = |e| explain_list_error(e).to_string().contains("deprecated by Dropbox");
+        assert!(gone(dropbox_sdk::Error::BadRequest(
+            "Unknown API function: \"paper/docs/list\"".to_owned())));
+        assert!(gone(dropbox_sdk::Error::AccessDenied(
+            AccessError::PaperAccessDenied(PaperAccessError::PaperDisabled))));
+        assert!(!gone(dropbox_sdk::Error::BadRequest(
+            "Error in call to API function \"paper/docs/list\": request body: unknown field `x`"
+                .to_owned())));
+        assert!(!gone(dropbox_sdk::Error::AccessDenied(
+            AccessError::PaperAccessDenied(PaperAccessError::NotPaperUser))));
+    }
+
+    #[test]
+    fn strip_comments_keeps_images() {
+        let config = test_config();
+        let html = concat!(
+            r#"<p>a<!-- <img src="hidden.png"> --><img src="https://example.com/a.png">"#,
+            r#"<!--[if IE]><img src="ie.png"><![endif]--><img alt="b" src='b.png'><!-- x"#);
+        let stripped = strip_comments(html.as_bytes());
+        assert_eq!(String::from_utf8_lossy(&stripped), concat!(
+            r#"<p>a<img src="https://example.com/a.png">"#,
+            r#"<!--[if IE]><img src="ie.png"><![endif]--><img alt="b" src='b.png'><!-- x"#));
+        let base = Url::parse("https://paper.dropbox.com/doc/abc").unwrap();
+        let urls = find_images(&stripped, &config.img_re, &base, &mut String::new())
+            .into_iter()
+            .map(|(_, _, url)| url)
+            .collect::<Vec<_>>();
+        assert_eq!(urls, [
+            "https://example.com/a.png",
+            "https://paper.dropbox.com/doc/ie.png",
+            "https://paper.dropbox.com/doc/b.png",
+        ]);
+    }
+
+    #[test]
+    fn header_revision_reads_the_download_header() {
+        let dir = test_dir("header_revision");
+        let doc = dir.join("doc.html");
+        fs::write(&doc, "<!DOCTYPE html><html><head><title>T</title></head><body><p>downloaded \
+            rev 42 on Tue, 1 Jul 2003 10:52:37 +0200 from <a href=\"x\">x</a><br>").unwrap();
+        assert_eq!(header_revision(&doc), Some(42));
+        fs::write(&doc, "# Markdown has no header\n").unwrap();
+        assert_eq!(header_revision(&doc), None);
+        assert_eq!(header_revision(&dir.join("missing.html")), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn img_re_handles_every_quoting_style() {
+        let config = test_config();
+        for (html, url) in &[
+            (r#"<img alt="a" src="https://example.com/a.png">"#, "https://example.com/a.png"),
+            (r#"<img alt='b' src='https://example.com/b.png'>"#, "https://example.com/b.png"),
+            (r#"<img alt=c src=https://example.com/c.png>"#, "https://example.com/c.png"),
+            // With src first, and attributes after it.
+            (r#"<img src="d.png" alt="d" width="10">"#, "d.png"),
+            (r#"<img src='e.png' alt='e'>"#, "e.png"),
+            (r#"<img src=f.png alt=f>"#, "f.png"),
+        ] {
+            let c = config.img_re.captures(html.as_bytes()).unwrap();
+            assert_eq!(std::str::from_utf8(captured_url(&c)).unwrap(), *url, "in {}", html);
+        }
+        assert!(config.img_re.captures(br#"<img alt="no src">"#).is_none());
+    }
+
+    #[test]
+    fn find_images_resolves_against_the_doc() {
+        let config = test_config();
+        let base = Url::parse("https://paper.dropbox.com/doc/Title-abc123").unwrap();
+        let html = concat!(
+            r#"<img src="https://dropbox.com/a.png?w=1">"#,
+            r#"<img alt="b" src='//cdn.example.com/b.png'>"#,
+            r#"<img src=./c.png>"#,
+            r#"<img src="/d.png">"#,
+            r#"<img src="data:image/png;base64,AAAA">"#);
+        let images = find_images(html.as_bytes(), &config.img_re, &base, &mut String::new());
+        let found = images.iter()
+            .map(|(_, tag, url)| (tag.as_str(), url.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [
+            (r#"<img src="https://dropbox.com/a.png?w=1">"#, "https://dropbox.com/a.png?w=1"),
+            (r#"<img alt="b" src="https://cdn.example.com/b.png">"#,
+                "https://cdn.example.com/b.png"),
+            (r#"<img src="https://paper.dropbox.com/doc/c.png">"#,
+                "https://paper.dropbox.com/doc/c.png"),
+            (r#"<img src="https://paper.dropbox.com/d.png">"#, "https://paper.dropbox.com/d.png"),
+        ]);
+        // The ranges are for the whole tag, so it can be replaced.
+        assert_eq!(&html[images[1].0.clone()], r#"<img alt="b" src='//cdn.example.com/b.png'>"#);
+    }
+
+    #[test]
+    fn fetch_doc_from_a_mock_source() {
+        let dir = test_dir("fetch_doc");
+        let mut config = test_config();
+        config.out_dir = dir.clone();
+        let config = Arc::new(config);
+        let source = Arc::new(source::MockSource::default());
+        source.add("abc123", "A doc", 1, "<html><body><p>Hello</p></body></html>");
+        let client: Arc<dyn PaperSource> = source.clone();
+        let images_pool = Arc::new(Mutex::new(ThreadPool::new(1)));
+        let doc_map = Arc::new(Mutex::new(HashMap::new()));
+        let fetch = |id| fetch_doc(id, Arc::clone(&client), Arc::clone(&config),
+            Arc::clone(&images_pool), Arc::clone(&doc_map));
+
+        let report = fetch("abc123");
+        assert_eq!(report.status, DocStatus::Ok, "{}", report.log);
+        assert_eq!(report.title.as_deref(), Some("A doc"));
+        let path = doc_map.lock().unwrap()["https://paper.dropbox.com/doc/abc123"].path.clone();
+        assert_eq!(path, "A doc (abc123).html");
+        let html = fs::read_to_string(dir.join(&path)).unwrap();
+        assert!(html.contains("<title>A doc</title>"));
+        assert!(html.contains("downloaded rev 1"));
+        assert!(html.contains("<p>Hello</p>"));
+
+        // Nothing's changed, so there's nothing to do.
+        let report = fetch("abc123");
+        assert_eq!(report.status, DocStatus::Skip(SkipReason::Unchanged), "{}", report.log);
+
+        // A new revision gets downloaded again.
+        source.add("abc123", "A doc", 2, "<html><body><p>Hello again</p></body></html>");
+        let report = fetch("abc123");
+        assert_eq!(report.status, DocStatus::Ok, "{}", report.log);
+        assert!(fs::read_to_string(dir.join(&path)).unwrap().contains("Hello again"));
+        assert_eq!(doc_map.lock().unwrap()["https://paper.dropbox.com/doc/abc123"].revision,
+            Some(2));
+
+        // And one that isn't there fails, without leaving anything behind.
+        let report = fetch("missing");
+        assert!(matches!(report.status, DocStatus::Fail(_)), "{}", report.log);
+        assert_eq!(doc_map.lock().unwrap().len(), 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sanitize_filename_keeps_non_ascii() {
+        assert_eq!(sanitize_filename("Café résumé"), "Café résumé");
+        assert_eq!(sanitize_filename("会議メモ: 2024/05"), "会議メモ_ 2024_05");
+        assert_eq!(sanitize_filename("🚀 Launch plan 🎉"), "🚀 Launch plan 🎉");
+        // Combining characters and zero-width joiners aren't control characters either.
+        assert_eq!(sanitize_filename("Cafe\u{301} 👩\u{200d}💻"), "Cafe\u{301} 👩\u{200d}💻");
+        assert_eq!(sanitize_filename("tab\there\u{7f}"), "tab_here_");
+    }
+
+    #[test]
+    fn sanitize_filename_avoids_reserved_names() {
+        assert_eq!(sanitize_filename("CON"), "_CON");
+        assert_eq!(sanitize_filename("nul"), "_nul");
+        assert_eq!(sanitize_filename("COM1"), "_COM1");
+        assert_eq!(sanitize_filename("lpt9.txt"), "_lpt9.txt");
+        assert_eq!(sanitize_filename("aux "), "_aux");
+        assert_eq!(sanitize_filename("Contacts"), "Contacts");
+        assert_eq!(sanitize_filename("COM0"), "COM0");
+        assert_eq!(sanitize_filename("COM10"), "COM10");
+
+        // Windows drops trailing dots and spaces, so they shouldn't be there to begin with.
+        assert_eq!(sanitize_filename("Notes."), "Notes");
+        assert_eq!(sanitize_filename("Notes. . "), "Notes");
+        assert_eq!(sanitize_filename("Con. "), "_Con");
+        assert_eq!(sanitize_filename("..."), "");
+    }
+
+    #[test]
+    fn long_titles_make_creatable_names() {
+        let dir = test_dir("long_titles");
+        let mut config = test_config();
+        config.gzip = true;
+        for title in ["a".repeat(500), "é".repeat(500), "会".repeat(500), "🚀".repeat(500)] {
+            let filename = doc_filename(&title, "AbCdEfGhIjKlMnOpQrStU", &config);
+            assert!(filename.len() <= 255, "{} bytes", filename.len());
+            assert!(filename.ends_with(" (AbCdEfGhIjKlMnOpQrStU).html.gz"));
+            File::create(dir.join(&filename)).unwrap();
+
+            let folder = folder_dir_name(&title);
+            assert!(folder.len() <= MAX_TITLE_BYTES);
+            fs::create_dir(dir.join(&folder)).unwrap();
+        }
+        // Truncating shouldn't leave a trailing dot or space behind.
+        let title = format!("{} x", "a".repeat(MAX_TITLE_BYTES - 1));
+        assert_eq!(folder_dir_name(&title), "a".repeat(MAX_TITLE_BYTES - 1));
+        let prefix = format!("{} (", "a".repeat(MAX_TITLE_BYTES - 1));
+        assert!(doc_filename(&title, "id", &config).starts_with(&prefix));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_dump_zip_skips_itself() {
+        let dir = test_dir("dump_zip");
+        let config = test_config();
+        fs::create_dir_all(dir.join("images")).unwrap();
+        fs::create_dir_all(dir.join("zips")).unwrap();
+        fs::write(dir.join("A (a).html"), "<p>a</p>").unwrap();
+        fs::write(dir.join("images/x.png"), "png").unwrap();
+        fs::write(dir.join("zips/Folder.zip"), "zip").unwrap();
+        fs::write(dir.join("list.json.tmp"), "partial").unwrap();
+
+        // The second time, the zip from the first is there too, and mustn't end up inside itself.
+        let zip_path = dir.join("all.zip");
+        for _ in 0 .. 2 {
+            write_dump_zip(&dir, &zip_path, &config).unwrap();
+            let data = fs::read(&zip_path).unwrap();
+            let count = u16::from_le_bytes([data[data.len() - 12], data[data.len() - 11]]);
+            assert_eq!(count, 2);
+        }
+        assert!(!dir.join("all.zip.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_failures_get_tried_again() {
+        let dir = test_dir("write_failure");
+        let mut config = test_config();
+        config.out_dir = dir.clone();
+        config.keep_raw = true;
+        let config = Arc::new(config);
+        let source = Arc::new(source::MockSource::default());
+        source.add("abc123", "A doc", 1, "<html><body><p>Hello</p></body></html>");
+        let client: Arc<dyn PaperSource> = source;
+        let doc_map = Arc::new(Mutex::new(HashMap::new()));
+
+        // Something in the way of the raw file makes writing it fail.
+        fs::create_dir(dir.join("A doc (abc123).raw.html")).unwrap();
+        let report = fetch_doc("abc123", client, Arc::clone(&config),
+            Arc::new(Mutex::new(ThreadPool::new(1))), Arc::clone(&doc_map));
+        assert!(matches!(report.status, DocStatus::Fail(_)), "{}", report.log);
+        assert!(doc_map.lock().unwrap().is_empty());
+        assert!(!dir.join("A doc (abc123).html").exists());
+        assert_eq!(*config.gave_up.lock().unwrap(), ["abc123"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn data_uri_src_with_srcset() {
+        let dir = test_dir("data_uri_srcset");
+        let cache = test_dir("data_uri_srcset_cache");
+        fs::create_dir(dir.join("images")).unwrap();
+        // The srcset image comes from the cache, so nothing needs the network.
+        fs::write(cache.join(hash_bytes(b"https://paper.dropbox.com/doc/x.png")), "big").unwrap();
+        let mut config = test_config();
+        config.out_dir = dir.clone();
+        config.extract_data_uris = true;
+        config.image_cache = Some(cache.clone());
+        let config = Arc::new(config);
+        let source = Arc::new(source::MockSource::default());
+        source.add("abc123", "A doc", 1, concat!(
+            r#"<html><body><img src="data:image/png;base64,iVBORw0KGgo=" srcset="x.png 2x">"#,
+            r#"<img srcset="x.png 2x"></body></html>"#));
+        let report = fetch_doc("abc123", source, Arc::clone(&config),
+            Arc::new(Mutex::new(ThreadPool::new(1))), Arc::new(Mutex::new(HashMap::new())));
+        assert_eq!(report.status, DocStatus::Ok, "{}", report.log);
+
+        let html = fs::read_to_string(dir.join("A doc (abc123).html")).unwrap();
+        let extracted = format!("images/{}.png", hash_bytes(b"\x89PNG\r\n\x1a\n"));
+        let srcset = format!("images/x __{}.png",
+            hash_bytes(b"https://paper.dropbox.com/doc/x.png"));
+        assert!(html.contains(&format!(r#"<img src="{}" srcset="{}">"#, extracted, srcset)), "{}",
+            html);
+        assert!(html.contains(&format!(r#"<img src="{}">"#, srcset)), "{}", html);
+        assert!(dir.join(&extracted).exists());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn largest_srcset_candidate_by_density_and_width() {
+        assert_eq!(largest_srcset_candidate("a.png, b.png 2x, c.png 1.5x"), Some("b.png"));
+        assert_eq!(largest_srcset_candidate("a.png 2x,b.png 3x"), Some("b.png"));
+        assert_eq!(largest_srcset_candidate("small.jpg 320w, big.jpg 640w, mid.jpg 480w"),
+            Some("big.jpg"));
+        assert_eq!(largest_srcset_candidate("only.png"), Some("only.png"));
+        assert_eq!(largest_srcset_candidate("  "), None);
+    }
+
+    /// Serve `body` as `content_type` over HTTP on localhost, to every request, for as long as the
+    /// test runs. Returns the base URL.
+    fn serve(body: &'static [u8], content_type: &'static str) -> String {
+        serve_counted(body, content_type, true).0
+    }
+
+    /// Like [`serve`], but also counts the requests, and can leave out the Content-Length.
+    fn serve_counted(body: &'static [u8], content_type: &'static str, send_len: bool)
+        -> (String, Arc<AtomicUsize>)
+    {
+        use std::net::TcpListener;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&requests);
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while find_bytes(&request, b"\r\n\r\n").is_none() {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[.. n]),
+                    }
+                }
+                let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\n", content_type);
+                if send_len {
+                    let _ = write!(stream, "Content-Length: {}\r\n", body.len());
+                }
+                let _ = write!(stream, "Connection: close\r\n\r\n");
+                let _ = stream.write_all(body);
+            }
+        });
+        (format!("http://{}", addr), requests)
+    }
+
+    #[test]
+    fn srcset_images_get_inlined() {
+        let server = serve(b"\x89PNG\r\n\x1a\n", "image/png");
+        let dir = test_dir("srcset_inline");
+        let mut config = test_config();
+        config.out_dir = dir.clone();
+        config.inline_images = Some(65536);
+        let config = Arc::new(config);
+        let source = Arc::new(source::MockSource::default());
+        source.add("abc123", "A doc", 1, &format!(
+            r#"<html><body><img srcset="{0}/a.png 1x, {0}/b.png 2x"></body></html>"#, server));
+        let report = fetch_doc("abc123", source, Arc::clone(&config),
+            Arc::new(Mutex::new(ThreadPool::new(2))), Arc::new(Mutex::new(HashMap::new())));
+        assert_eq!(report.status, DocStatus::Ok, "{}", report.log);
+        assert_eq!(report.images, Some((1, 1)));
+
+        let html = fs::read_to_string(dir.join("A doc (abc123).html")).unwrap();
+        assert!(html.contains(r#"<img src="data:image/png;base64,iVBORw0KGgo=">"#), "{}", html);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn find_css_images_handles_every_quoting_style() {
+        let config = test_config();
+        let base = Url::parse("https://paper.dropbox.com/doc/Title-abc123").unwrap();
+        let html = concat!(
+            r#"<div style="background: url(a.png)">"#,
+            r#"<div style="background-image: url('b.png')">"#,
+            r#"<div style='background-image: url( "c.png" )'>"#,
+            r#"<div style="background-image: url(&quot;d.png&quot;)">"#,
+            r#"<div style="background: url(data:image/png;base64,AAAA)">"#,
+            r#"<div style="background: linear-gradient(to right, red, blue)">"#,
+            r#"<div style="background: linear-gradient(red, blue), url(//cdn.example.com/e.png)">"#,
+            r#"<img style="background: url(f.png)" src="g.png">"#);
+        let images = find_css_images(html.as_bytes(), &config, &base, &mut String::new());
+        let found = images.iter()
+            .map(|(range, css, url)| (&html[range.clone()], css.as_str(), url.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [
+            ("url(a.png)", "url('https://paper.dropbox.com/doc/a.png')",
+                "https://paper.dropbox.com/doc/a.png"),
+            ("url('b.png')", "url('https://paper.dropbox.com/doc/b.png')",
+                "https://paper.dropbox.com/doc/b.png"),
+            (r#"url( "c.png" )"#, r#"url("https://paper.dropbox.com/doc/c.png")"#,
+                "https://paper.dropbox.com/doc/c.png"),
+            ("url(&quot;d.png&quot;)", "url('https://paper.dropbox.com/doc/d.png')",
+                "https://paper.dropbox.com/doc/d.png"),
+            ("url(//cdn.example.com/e.png)", "url('https://cdn.example.com/e.png')",
+                "https://cdn.example.com/e.png"),
+        ]);
+    }
+
+    #[test]
+    fn gzipped_docs_can_be_read_back() {
+        let dir = test_dir("gzipped_docs");
+        let mut config = test_config();
+        config.out_dir = dir.clone();
+        config.gzip = true;
+        let config = Arc::new(config);
+        let source = Arc::new(source::MockSource::default());
+        source.add("aaa", "A", 1,
+            r#"<html><body><a href="https://paper.dropbox.com/doc/B-bbb">B</a></body></html>"#);
+        source.add("bbb", "B", 1, r#"<html><body><a href="nowhere.html">?</a></body></html>"#);
+        let client: Arc<dyn PaperSource> = source;
+        let doc_map = Arc::new(Mutex::new(HashMap::new()));
+        for id in ["aaa", "bbb"] {
+            let report = fetch_doc(id, Arc::clone(&client), Arc::clone(&config),
+                Arc::new(Mutex::new(ThreadPool::new(1))), Arc::clone(&doc_map));
+            assert_eq!(report.status, DocStatus::Ok, "{}", report.log);
+        }
+
+        let docs = rebuild_doc_list(&dir).unwrap().docs;
+        let found = docs.iter()
+            .map(|doc| (doc.id.as_str(), doc.path.as_str(), doc.revision))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [
+            ("aaa", "A (aaa).html.gz", Some(1)),
+            ("bbb", "B (bbb).html.gz", Some(1)),
+        ]);
+
+        let link_re = Regex::new(LINK_RE).unwrap();
+        link_docs_locally(&dir, &docs, &link_re).unwrap();
+        let html = String::from_utf8(read_doc(&dir.join("A (aaa).html.gz")).unwrap()).unwrap();
+        assert!(html.contains(r#"<a href="B%20%28bbb%29.html.gz">B</a>"#), "{}", html);
+
+        let broken = check_local_links(&dir, &docs, &config.img_re, &link_re).unwrap();
+        let broken = broken.iter()
+            .map(|link| (link.file.file_name().unwrap().to_str().unwrap(), link.target.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(broken, [("B (bbb).html.gz", "nowhere.html")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn table_of_contents_links_every_heading() {
+        let config = test_config();
+        let html = concat!(
+            r#"<h1>Caf&#233; <b>menu</b></h1><p>x</p>"#,
+            r#"<h2 id="drinks">Drinks</h2>"#,
+            r#"<h3>Hot<br>and cold</h3>"#,
+            r#"<h2>Caf&#233; menu</h2>"#);
+        let mut replacements = vec![];
+        let toc = table_of_contents(html.as_bytes(), &config, &mut replacements);
+        assert_eq!(toc, concat!(
+            r##"<nav class="toc"><ul><li><a href="#caf-menu">Café menu</a>"##,
+            r##"<ul><li><a href="#drinks">Drinks</a>"##,
+            r##"<ul><li><a href="#hot-and-cold">Hot and cold</a></li></ul></li>"##,
+            r##"<li><a href="#caf-menu-2">Café menu</a></li></ul></li></ul></nav>"##,
+            "\n"));
+        let inserted = replacements.iter()
+            .map(|(start, end, id, _)| {
+                (*start, *end - *start, String::from_utf8_lossy(id).into_owned())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(inserted, [
+            (3, 0, r#" id="caf-menu""#.to_owned()),
+            (html.find("<h3").unwrap() + 3, 0, r#" id="hot-and-cold""#.to_owned()),
+            (html.rfind("<h2").unwrap() + 3, 0, r#" id="caf-menu-2""#.to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn folder_zips_of_gzipped_docs_have_their_images() {
+        let dir = test_dir("zip_gzip");
+        let cache = test_dir("zip_gzip_cache");
+        fs::create_dir(dir.join("images")).unwrap();
+        fs::write(cache.join(hash_bytes(b"https://paper.dropbox.com/doc/x.png")), "png").unwrap();
+        let mut config = test_config();
+        config.out_dir = dir.clone();
+        config.gzip = true;
+        config.image_cache = Some(cache.clone());
+        let config = Arc::new(config);
+        let source = Arc::new(source::MockSource::default());
+        source.add("abc123", "A doc", 1, r#"<html><body><img src="x.png"></body></html>"#);
+        let doc_map = Arc::new(Mutex::new(HashMap::new()));
+        let report = fetch_doc("abc123", source, Arc::clone(&config),
+            Arc::new(Mutex::new(ThreadPool::new(1))), Arc::clone(&doc_map));
+        assert_eq!(report.status, DocStatus::Ok, "{}", report.log);
+
+        let docs = doc_map.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        write_folder_zips(&dir, &docs, &config).unwrap();
+        let zip = fs::read(dir.join("zips/(no folder).zip")).unwrap();
+        let image = format!("images/x __{}.png",
+            hash_bytes(b"https://paper.dropbox.com/doc/x.png"));
+        assert!(find_bytes(&zip, image.as_bytes()).is_some());
+        // The doc goes in still compressed.
+        let doc = fs::read(dir.join("A doc (abc123).html.gz")).unwrap();
+        assert!(find_bytes(&zip, &doc).is_some());
+        assert!(find_bytes(&zip, b"A doc (abc123).html.gz").is_some());
+        assert!(dir.join("zips/index.html").exists());
+        assert!(!dir.join("zips/(no folder).zip.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn overlapping_replacements_dont_lose_the_doc() {
+        let dir = test_dir("overlapping");
+        let cache = test_dir("overlapping_cache");
+        fs::create_dir(dir.join("images")).unwrap();
+        let base = Url::parse("https://paper.dropbox.com/doc/abc123").unwrap();
+        // An image tag inside a style's url(), which both the image and the CSS regexes find.
+        let css_url = String::from(base.join("<img src=x.png srcset='y.png 2x'>").unwrap());
+        for url in [css_url.as_str(), "https://paper.dropbox.com/doc/x.png",
+            "https://paper.dropbox.com/doc/y.png"]
+        {
+            fs::write(cache.join(hash_bytes(url.as_bytes())), "png").unwrap();
+        }
+        let mut config = test_config();
+        config.out_dir = dir.clone();
+        config.toc = true;
+        config.image_cache = Some(cache.clone());
+        let config = Arc::new(config);
+        let source = Arc::new(source::MockSource::default());
+        source.add("abc123", "A doc", 1, concat!(
+            r#"<html><body><h1>Title</h1>"#,
+            r#"<div style="background: url(&quot;<img src=x.png srcset='y.png 2x'>&quot;)">"#,
+            r#"</div></body></html>"#));
+        let report = fetch_doc("abc123", source, Arc::clone(&config),
+            Arc::new(Mutex::new(ThreadPool::new(1))), Arc::new(Mutex::new(HashMap::new())));
+        assert_eq!(report.status, DocStatus::Ok, "{}", report.log);
+
+        let html = fs::read_to_string(dir.join("A doc (abc123).html")).unwrap();
+        assert!(html.contains(r#"<h1 id="title">Title</h1>"#), "{}", html);
+        // The first one, the url(), wins.
+        assert!(html.contains(r#"<div style="background: url('images/"#), "{}", html);
+        assert!(!html.contains("<img"), "{}", html);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+
+    #[test]
+    fn args_fill_in_the_config() {
+        let dir = test_dir("args");
+        let args = ["--output-dir", dir.to_str().unwrap(), "--format", "markdown",
+            "--page-threads", "3", "--doc-id", "abc123", "--filter-by", "owned", "--no-backup",
+            "--compact"];
+        let config = Config::from_args(args.iter().map(|s| s.to_string())).unwrap();
+        assert_eq!(config.out_dir, dir);
+        assert_eq!(config.format, OutputFormat::Markdown);
+        assert_eq!(config.page_threads, 3);
+        assert_eq!(config.doc_id.as_deref(), Some("abc123"));
+        assert!(config.only_owned);
+        assert_eq!(config.list_backups, 0);
+        assert!(config.compact);
+        assert!(config.dumping());
+        // Dumping opens the error log up front.
+        assert!(config.error_log.is_some());
+        assert!(dir.join("errors.log").exists());
+
+        // Just listing doesn't write anything.
+        let list_dir = dir.join("list");
+        let args = ["--output-dir", list_dir.to_str().unwrap(), "--list-only"];
+        let config = Config::from_args(args.iter().map(|s| s.to_string())).unwrap();
+        assert!(!config.dumping());
+        assert!(config.error_log.is_none());
+        assert!(!list_dir.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn too_big_to_inline_is_only_fetched_once() {
+        let body: &[u8] = b"\x89PNG\r\n\x1a\n and then a lot more image than fits in a data URI";
+        for send_len in [false, true] {
+            let (server, requests) = serve_counted(body, "image/png", send_len);
+            let dir = test_dir(&format!("inline_too_big_{}", send_len));
+            fs::create_dir_all(dir.join("images")).unwrap();
+            let mut config = test_config();
+            config.out_dir = dir.clone();
+            let url = format!("{}/big", server);
+            let started = match fetch_image_inline(&url, &config, 16) {
+                Inlined::TooBig(started) => started,
+                Inlined::Done(result) => panic!("inlined: {:?}", result),
+            };
+            let path = fetch_image(&url, &config, Some(started)).unwrap();
+            assert!(path.ends_with(".png"), "{}", path);
+            assert_eq!(fs::read(dir.join(&path)).unwrap(), body);
+            assert_eq!(requests.load(Ordering::SeqCst), 1);
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+
+    #[test]
+    fn owner_filter_ignores_case_beyond_ascii() {
+        let dir = test_dir("owner_filter");
+        let args = ["--output-dir", dir.to_str().unwrap(), "--no-export",
+            "--owner", "ÅSA@Example.com"];
+        let config = Arc::new(Config::from_args(args.iter().map(|s| s.to_string())).unwrap());
+        let source = Arc::new(source::MockSource::default());
+        source.add("abc123", "Åsa's", 1, "<html></html>");
+        source.add("def456", "Someone else's", 1, "<html></html>");
+        source.docs.lock().unwrap().get_mut("abc123").unwrap().owner =
+            "åsa@example.com".to_owned();
+        for (id, status) in [("abc123", DocStatus::Ok),
+            ("def456", DocStatus::Skip(SkipReason::Filtered))]
+        {
+            let report = fetch_doc(id, source.clone(), Arc::clone(&config),
+                Arc::new(Mutex::new(ThreadPool::new(1))), Arc::new(Mutex::new(HashMap::new())));
+            assert_eq!(report.status, status, "{}", report.log);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+
+    #[test]
+    fn newlines_are_converted() {
+        let input: &[u8] = b"lf\ncrlf\r\ncr\rblank\n\n\r\n\r\rend";
+        for (style, expected) in [
+            (Newlines::Lf, &b"lf\ncrlf\ncr\nblank\n\n\n\n\nend"[..]),
+            (Newlines::CrLf, &b"lf\r\ncrlf\r\ncr\r\nblank\r\n\r\n\r\n\r\n\r\nend"[..]),
+            (Newlines::Preserve, input),
+        ] {
+            assert_eq!(style.apply(input.to_vec()), expected, "{:?}", style);
+
+            // The same, a byte at a time, so every CRLF is split across two writes.
+            let mut out = NewlineWriter::new(vec![], style);
+            for b in input {
+                out.write_all(&[*b]).unwrap();
+            }
+            assert_eq!(out.finish().unwrap(), expected, "{:?}", style);
+        }
+
+        // A CR at the end of one write and an LF at the start of the next are one line ending,
+        // but not if there's anything in between.
+        for style in [Newlines::Lf, Newlines::CrLf] {
+            let mut out = NewlineWriter::new(vec![], style);
+            out.write_all(b"a\r").unwrap();
+            out.write_all(b"\nb\r").unwrap();
+            out.write_all(b"c").unwrap();
+            out.write_all(b"\n").unwrap();
+            let newline: &[u8] = if style == Newlines::CrLf { b"\r\n" } else { b"\n" };
+            assert_eq!(out.finish().unwrap(),
+                [b"a", newline, b"b", newline, b"c", newline].concat());
+        }
+    }
+}
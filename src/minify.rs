@@ -0,0 +1,136 @@
+//! Shrinking HTML by taking out what a browser ignores anyway: comments, and runs of whitespace.
+//! Whitespace between tags is only collapsed, not removed, since next to inline elements it
+//! matters. Anything in `<pre>`, `<textarea>`, `<script>` or `<style>` is left exactly as it is.
+
+use crate::find_bytes;
+
+/// Elements whose contents are copied as they are.
+const VERBATIM_ELEMENTS: &[&[u8]] = &[b"pre", b"textarea", b"script", b"style"];
+
+fn find_ignoring_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w.eq_ignore_ascii_case(needle))
+}
+
+/// Where the element starting at `html[0]` (a `<`) ends, if it's one of [`VERBATIM_ELEMENTS`].
+fn verbatim_end(html: &[u8]) -> Option<Result<usize, String>> {
+    let name_len = html[1 ..].iter().take_while(|b| b.is_ascii_alphanumeric()).count();
+    let name = html[1 .. 1 + name_len].to_ascii_lowercase();
+    if !VERBATIM_ELEMENTS.contains(&&name[..]) {
+        return None;
+    }
+    let closing = [&b"</"[..], &name].concat();
+    Some(find_ignoring_case(&html[1 ..], &closing)
+        .and_then(|close| find_bytes(&html[1 + close ..], b">").map(|end| 1 + close + end + 1))
+        .ok_or_else(|| format!("unterminated <{}>", String::from_utf8_lossy(&name))))
+}
+
+/// Minify a whole HTML doc. Fails if it can't tell where a comment or verbatim element ends, rather
+/// than risk mangling the rest of the doc.
+pub fn minify(html: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(html.len());
+    let mut pos = 0;
+    while pos < html.len() {
+        let rest = &html[pos ..];
+        if rest.starts_with(b"<!--") {
+            let end = find_bytes(&rest[4 ..], b"-->").ok_or("unterminated comment")? + 4 + 3;
+            // Conditional comments do something, so they stay.
+            if rest.starts_with(b"<!--[if") || rest.starts_with(b"<!--<![endif]") {
+                out.extend_from_slice(&rest[.. end]);
+            }
+            pos += end;
+        } else if rest[0] == b'<' && rest.get(1).is_some_and(|b| b.is_ascii_alphabetic()) {
+            if let Some(end) = verbatim_end(rest) {
+                let end = end?;
+                out.extend_from_slice(&rest[.. end]);
+                pos += end;
+                continue;
+            }
+            // A tag: whitespace between attributes is collapsed, but quoted values are kept.
+            let mut quote = None;
+            let mut space = false;
+            let mut i = 0;
+            while i < rest.len() {
+                let b = rest[i];
+                i += 1;
+                match quote {
+                    Some(q) => {
+                        out.push(b);
+                        if b == q {
+                            quote = None;
+                        }
+                    }
+                    None if b.is_ascii_whitespace() => space = true,
+                    None => {
+                        if space && b != b'>' && b != b'=' && out.last() != Some(&b'=') {
+                            out.push(b' ');
+                        }
+                        space = false;
+                        out.push(b);
+                        if b == b'"' || b == b'\'' {
+                            quote = Some(b);
+                        } else if b == b'>' {
+                            break;
+                        }
+                    }
+                }
+            }
+            pos += i;
+        } else if rest[0].is_ascii_whitespace() {
+            // Keep a line break if there was one, so the result isn't all on one line.
+            let len = rest.iter().take_while(|b| b.is_ascii_whitespace()).count();
+            out.push(if rest[.. len].contains(&b'\n') { b'\n' } else { b' ' });
+            pos += len;
+        } else {
+            out.push(rest[0]);
+            pos += 1;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn minified(html: &str) -> String {
+        String::from_utf8(minify(html.as_bytes()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn verbatim_elements_are_left_alone() {
+        for html in [
+            "<pre>  two  spaces\n\n  and <!-- a comment --></pre>",
+            "<PRE class=\"x\">  upper  case  </PRE>",
+            "<textarea>\n  typed   text\n</textarea>",
+            "<script>if (a  <  b) { x = '<!-- -->'; }</script>",
+            "<style>p  >  b { color:  red }</style>",
+        ] {
+            assert_eq!(minified(html), html);
+        }
+        assert_eq!(minified("<p>a   b</p>  <pre> x  y </pre>  <p>c   d</p>"),
+            "<p>a b</p> <pre> x  y </pre> <p>c d</p>");
+    }
+
+    #[test]
+    fn whitespace_is_collapsed_not_removed() {
+        assert_eq!(minified("<b>bold</b>   <i>italic</i>"), "<b>bold</b> <i>italic</i>");
+        assert_eq!(minified("<p>one\n\n   two\tthree</p>"), "<p>one\ntwo three</p>");
+        assert_eq!(minified("<p>x</p>\n\n\n<p>y</p>"), "<p>x</p>\n<p>y</p>");
+        assert_eq!(minified("<img   src=\"a b.png\"\n   alt = 'x  y' >"),
+            "<img src=\"a b.png\" alt='x  y'>");
+    }
+
+    #[test]
+    fn comments_are_stripped() {
+        assert_eq!(minified("<p>a<!-- gone --></p><!--\nmultiple\nlines\n--><p>b</p>"),
+            "<p>a</p><p>b</p>");
+        // Conditional comments do something, so they stay.
+        let conditional = "<!--[if IE]><p>IE</p><![endif]--><!--<![endif]-->";
+        assert_eq!(minified(conditional), conditional);
+        // Things that look like comments in attribute values aren't comments.
+        let attr = r#"<a title="<!-- not a comment -->" href='x'>x</a>"#;
+        assert_eq!(minified(attr), attr);
+        assert!(minify(b"<p>a<!-- never ends</p>").is_err());
+        assert!(minify(b"<pre>never ends").is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! Where docs come from. Everything that talks to the Paper API goes through [`PaperSource`], so
+//! that something else (like a canned set of docs) can stand in for Dropbox.
+
+use anyhow::{anyhow, Context, Result};
+use dropbox_sdk::client_trait::{Endpoint, HttpClient, HttpRequestResult, HttpRequestResultRaw,
+    ParamsType, Style, TeamSelect, UserAuthClient};
+use dropbox_sdk::default_client::TeamAuthDefaultClient;
+use dropbox_sdk::oauth2::{Authorization, TokenCache};
+use dropbox_sdk::paper::{self, DocLookupError, ExportFormat, FoldersContainingPaperDoc,
+    ListDocsCursorError, ListPaperDocsArgs, ListPaperDocsContinueArgs, ListPaperDocsResponse,
+    PaperDocExport, PaperDocExportResult, RefPaperDoc};
+use dropbox_sdk::team::{self, MembersGetInfoArgs, MembersGetInfoItem, UserSelectorArg};
+use dropbox_sdk::NoError;
+use crate::throttle::RateLimiter;
+use std::sync::Arc;
+
+/// The Paper operations needed to dump docs.
+pub trait PaperSource: Send + Sync {
+    /// Get the first page of doc ids.
+    fn list(&self, args: &ListPaperDocsArgs)
+        -> dropbox_sdk::Result<Result<ListPaperDocsResponse, NoError>>;
+
+    /// Get the next page of doc ids, following on from a previous page's cursor.
+    fn list_continue(&self, cursor: &str)
+        -> dropbox_sdk::Result<Result<ListPaperDocsResponse, ListDocsCursorError>>;
+
+    /// Get a doc's metadata and, if `with_body` is set, its contents in the given format.
+    fn download(&self, id: &str, format: ExportFormat, with_body: bool)
+        -> dropbox_sdk::Result<Result<HttpRequestResult<PaperDocExportResult>, DocLookupError>>;
+
+    /// Get the folders a doc is in, outermost first.
+    fn folder_info(&self, id: &str)
+        -> dropbox_sdk::Result<Result<FoldersContainingPaperDoc, DocLookupError>>;
+}
+
+#[allow(deprecated)] // the Paper API is deprecated, but it's the only way to get at legacy docs
+impl<C: UserAuthClient + Send + Sync> PaperSource for C {
+    fn list(&self, args: &ListPaperDocsArgs)
+        -> dropbox_sdk::Result<Result<ListPaperDocsResponse, NoError>>
+    {
+        paper::docs_list(self, args)
+    }
+
+    fn list_continue(&self, cursor: &str)
+        -> dropbox_sdk::Result<Result<ListPaperDocsResponse, ListDocsCursorError>>
+    {
+        paper::docs_list_continue(self, &ListPaperDocsContinueArgs::new(cursor.to_owned()))
+    }
+
+    fn download(&self, id: &str, format: ExportFormat, with_body: bool)
+        -> dropbox_sdk::Result<Result<HttpRequestResult<PaperDocExportResult>, DocLookupError>>
+    {
+        // Asking for zero bytes of the body still gets the metadata.
+        let range = if with_body { None } else { Some(0) };
+        paper::docs_download(self, &PaperDocExport::new(id.to_owned(), format), range, range)
+    }
+
+    fn folder_info(&self, id: &str)
+        -> dropbox_sdk::Result<Result<FoldersContainingPaperDoc, DocLookupError>>
+    {
+        paper::docs_get_folder_info(self, &RefPaperDoc::new(id.to_owned()))
+    }
+}
+
+/// A team client acting as one of the team's members, so that user routes like Paper's see that
+/// member's docs.
+pub struct MemberClient(TeamAuthDefaultClient);
+
+impl MemberClient {
+    /// Look up the team member with the given email address, using a team token.
+    pub fn new(auth: Authorization, email: &str) -> Result<Self> {
+        let mut client = TeamAuthDefaultClient::new(Arc::new(TokenCache::new(auth)));
+        let args = MembersGetInfoArgs::new(vec![UserSelectorArg::Email(email.to_owned())]);
+        let info = match team::members_get_info(&client, &args) {
+            Ok(result) => result.context("team/members/get_info API err")?,
+            Err(dropbox_sdk::Error::BadRequest(msg)) => {
+                return Err(anyhow!("--as-member needs a team token, and Dropbox rejected this \
+                    one; authorize with a team app (run with --reauth): {}", msg));
+            }
+            Err(e) => return Err(e).context("team/members/get_info HTTP or transport err"),
+        };
+        let id = match info.into_iter().next() {
+            Some(MembersGetInfoItem::MemberInfo(info)) => info.profile.team_member_id,
+            _ => return Err(anyhow!("{} isn't a member of this team", email)),
+        };
+        client.select(Some(TeamSelect::User(id)));
+        Ok(Self(client))
+    }
+}
+
+impl HttpClient for MemberClient {
+    fn request(
+        &self,
+        endpoint: Endpoint,
+        style: Style,
+        function: &str,
+        params: String,
+        params_type: ParamsType,
+        body: Option<&[u8]>,
+        range_start: Option<u64>,
+        range_end: Option<u64>,
+    ) -> dropbox_sdk::Result<HttpRequestResultRaw> {
+        self.0.request(endpoint, style, function, params, params_type, body, range_start, range_end)
+    }
+}
+
+// With a member selected, user routes are allowed.
+impl UserAuthClient for MemberClient {}
+
+/// Another source, with every call waiting its turn under a rate limit.
+pub struct Throttled {
+    pub inner: Arc<dyn PaperSource>,
+    pub limiter: RateLimiter,
+}
+
+impl PaperSource for Throttled {
+    fn list(&self, args: &ListPaperDocsArgs)
+        -> dropbox_sdk::Result<Result<ListPaperDocsResponse, NoError>>
+    {
+        self.limiter.wait();
+        self.inner.list(args)
+    }
+
+    fn list_continue(&self, cursor: &str)
+        -> dropbox_sdk::Result<Result<ListPaperDocsResponse, ListDocsCursorError>>
+    {
+        self.limiter.wait();
+        self.inner.list_continue(cursor)
+    }
+
+    fn download(&self, id: &str, format: ExportFormat, with_body: bool)
+        -> dropbox_sdk::Result<Result<HttpRequestResult<PaperDocExportResult>, DocLookupError>>
+    {
+        self.limiter.wait();
+        self.inner.download(id, format, with_body)
+    }
+
+    fn folder_info(&self, id: &str)
+        -> dropbox_sdk::Result<Result<FoldersContainingPaperDoc, DocLookupError>>
+    {
+        self.limiter.wait();
+        self.inner.folder_info(id)
+    }
+}
+
+/// A stand-in for Dropbox for tests, serving a fixed set of docs.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockSource {
+    /// Keyed by doc ID. Locked so that tests can change a doc between runs.
+    pub docs: std::sync::Mutex<std::collections::HashMap<String, MockDoc>>,
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MockDoc {
+    pub title: String,
+    pub owner: String,
+    pub revision: i64,
+    pub html: String,
+}
+
+#[cfg(test)]
+impl MockSource {
+    pub fn add(&self, id: &str, title: &str, revision: i64, html: &str) {
+        self.docs.lock().unwrap().insert(id.to_owned(), MockDoc {
+            title: title.to_owned(),
+            owner: "someone@example.com".to_owned(),
+            revision,
+            html: html.to_owned(),
+        });
+    }
+}
+
+#[cfg(test)]
+impl PaperSource for MockSource {
+    fn list(&self, _args: &ListPaperDocsArgs)
+        -> dropbox_sdk::Result<Result<ListPaperDocsResponse, NoError>>
+    {
+        let mut ids = self.docs.lock().unwrap().keys().cloned().collect::<Vec<_>>();
+        ids.sort();
+        Ok(Ok(ListPaperDocsResponse::new(ids, paper::Cursor::new("end".to_owned()), false)))
+    }
+
+    fn list_continue(&self, _cursor: &str)
+        -> dropbox_sdk::Result<Result<ListPaperDocsResponse, ListDocsCursorError>>
+    {
+        Ok(Ok(ListPaperDocsResponse::new(vec![], paper::Cursor::new("end".to_owned()), false)))
+    }
+
+    fn download(&self, id: &str, _format: ExportFormat, with_body: bool)
+        -> dropbox_sdk::Result<Result<HttpRequestResult<PaperDocExportResult>, DocLookupError>>
+    {
+        let doc = match self.docs.lock().unwrap().get(id) {
+            Some(doc) => doc.clone(),
+            None => return Ok(Err(DocLookupError::DocNotFound)),
+        };
+        let body = if with_body { doc.html.into_bytes() } else { vec![] };
+        Ok(Ok(HttpRequestResult {
+            result: PaperDocExportResult::new(doc.owner, doc.title, doc.revision,
+                "text/html".to_owned()),
+            content_length: Some(body.len() as u64),
+            body: Some(Box::new(std::io::Cursor::new(body))),
+        }))
+    }
+
+    fn folder_info(&self, id: &str)
+        -> dropbox_sdk::Result<Result<FoldersContainingPaperDoc, DocLookupError>>
+    {
+        if self.docs.lock().unwrap().contains_key(id) {
+            Ok(Ok(FoldersContainingPaperDoc::default()))
+        } else {
+            Ok(Err(DocLookupError::DocNotFound))
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Turning a doc's HTML into plain text, for searching and indexing rather than reading.
+
+use crate::epub::ENTITIES;
+use regex::{Captures, Regex};
+use std::sync::OnceLock;
+
+/// Elements that get a blank line before and after.
+const PARAGRAPH_ELEMENTS: &[&str] = &[
+    "blockquote", "dl", "figure", "h1", "h2", "h3", "h4", "h5", "h6", "hr", "ol", "p", "pre",
+    "table", "ul",
+];
+
+/// Elements that start on a new line.
+const LINE_ELEMENTS: &[&str] = &[
+    "address", "article", "dd", "div", "dt", "footer", "header", "li", "section", "tr",
+];
+
+/// Replace entity references with the characters they stand for. Unknown ones are left alone.
+fn decode_entities(s: &str) -> String {
+    static ENTITY_RE: OnceLock<Regex> = OnceLock::new();
+    let entity_re = ENTITY_RE.get_or_init(|| {
+        Regex::new(r"&(?:#([0-9]+)|#[xX]([0-9a-fA-F]+)|([a-zA-Z][a-zA-Z0-9]*));").unwrap()
+    });
+    entity_re.replace_all(s, |c: &Captures<'_>| {
+        let code = match (c.get(1), c.get(2), c.get(3)) {
+            (Some(dec), _, _) => dec.as_str().parse().ok(),
+            (_, Some(hex), _) => u32::from_str_radix(hex.as_str(), 16).ok(),
+            (_, _, Some(name)) => match name.as_str() {
+                "amp" => Some('&' as u32),
+                "lt" => Some('<' as u32),
+                "gt" => Some('>' as u32),
+                "quot" => Some('"' as u32),
+                "apos" => Some('\'' as u32),
+                name => ENTITIES.iter().find(|(n, _)| *n == name).map(|(_, code)| *code),
+            },
+            _ => None,
+        };
+        match code.and_then(std::char::from_u32) {
+            // Non-breaking spaces are just spaces, as far as searching goes.
+            Some('\u{a0}') => " ".to_owned(),
+            Some(c) => c.to_string(),
+            None => c[0].to_owned(),
+        }
+    }).into_owned()
+}
+
+fn tag_re() -> &'static Regex {
+    static TAG_RE: OnceLock<Regex> = OnceLock::new();
+    TAG_RE.get_or_init(|| Regex::new(r"<(/?)\s*([a-zA-Z][a-zA-Z0-9]*)([^>]*)>").unwrap())
+}
+
+fn space_re() -> &'static Regex {
+    static SPACE_RE: OnceLock<Regex> = OnceLock::new();
+    SPACE_RE.get_or_init(|| Regex::new(r"[ \t\r\n]+").unwrap())
+}
+
+/// The text of a snippet of inline HTML, like a heading's contents, all on one line.
+pub fn inline_text(html: &str) -> String {
+    let text = tag_re().replace_all(html, |c: &Captures<'_>| {
+        if c[2].eq_ignore_ascii_case("br") { " " } else { "" }
+    });
+    decode_entities(space_re().replace_all(&text, " ").trim())
+}
+
+/// The text of an HTML doc: tags are dropped, block elements go on their own lines, list items get
+/// a "- " in front, and images are replaced with their alt text, if they have any.
+pub fn from_html(html: &str) -> String {
+    static STRIP_RE: OnceLock<Regex> = OnceLock::new();
+    static ALT_RE: OnceLock<Regex> = OnceLock::new();
+    let strip_re = STRIP_RE.get_or_init(|| {
+        Regex::new(
+            r"(?is)<head\b.*?</head\s*>|<script\b.*?</script\s*>|<style\b.*?</style\s*>|<!--.*?-->|<!DOCTYPE[^>]*>")
+            .unwrap()
+    });
+    let tag_re = tag_re();
+    let alt_re = ALT_RE.get_or_init(|| {
+        Regex::new(r#"(?i)\salt\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap()
+    });
+    let space_re = space_re();
+
+    let html = strip_re.replace_all(html, "");
+    let mut out = String::with_capacity(html.len() / 2);
+    let mut in_pre = false;
+    let mut last_end = 0;
+    let add_text = |out: &mut String, text: &str, in_pre: bool| {
+        if in_pre {
+            *out += &decode_entities(text);
+        } else {
+            let text = space_re.replace_all(text, " ");
+            let text = if out.is_empty() || out.ends_with('\n') { text.trim_start() } else { &text };
+            *out += &decode_entities(text);
+        }
+    };
+    for c in tag_re.captures_iter(&html) {
+        let m = c.get(0).unwrap();
+        add_text(&mut out, &html[last_end .. m.start()], in_pre);
+        last_end = m.end();
+
+        let name = c[2].to_ascii_lowercase();
+        let closing = !c[1].is_empty();
+        if name == "pre" {
+            in_pre = !closing;
+        }
+        if PARAGRAPH_ELEMENTS.contains(&name.as_str()) {
+            out += "\n\n";
+        } else if name == "br" {
+            out += "\n";
+        } else if LINE_ELEMENTS.contains(&name.as_str()) {
+            if !out.ends_with('\n') {
+                out += "\n";
+            }
+            if name == "li" && !closing {
+                out += "- ";
+            }
+        } else if name == "td" || name == "th" {
+            if !out.is_empty() && !out.ends_with(['\n', ' ']) {
+                out += " ";
+            }
+        } else if name == "img" {
+            if let Some(alt) = alt_re.captures(&c[3]) {
+                let alt = alt.get(1).or_else(|| alt.get(2)).unwrap().as_str().trim();
+                if !alt.is_empty() {
+                    add_text(&mut out, alt, false);
+                }
+            }
+        }
+    }
+    add_text(&mut out, &html[last_end ..], in_pre);
+
+    // Tidy up: no trailing spaces, and no more than one blank line in a row.
+    let mut text = String::with_capacity(out.len());
+    let mut blank = 0;
+    for line in out.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank += 1;
+            if blank > 1 || text.is_empty() {
+                continue;
+            }
+        } else {
+            blank = 0;
+        }
+        text += line;
+        text += "\n";
+    }
+    while text.ends_with("\n\n") {
+        text.pop();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entities_are_decoded() {
+        assert_eq!(decode_entities("a &amp; b &lt;c&gt; &quot;d&quot; &apos;e&apos;"),
+            "a & b <c> \"d\" 'e'");
+        assert_eq!(decode_entities("&#233;&#xE9;&#XE9; &mdash; caf&eacute;"),
+            "ééé — caf&eacute;");
+        assert_eq!(decode_entities("x&nbsp;y &#160;z"), "x y  z");
+        assert_eq!(decode_entities("&#xFFFFFFFF; & &;"), "&#xFFFFFFFF; & &;");
+    }
+
+    #[test]
+    fn blocks_go_on_their_own_lines() {
+        let html = "<!DOCTYPE html><html><head><title>Not this</title></head><body>\
+            <h1>Title</h1><p>One <b>bold</b>\n   word.</p>\
+            <div>Line</div><div>Another<br>break</div>\
+            <script>var x = 1;</script><style>p { }</style><!-- or this -->\
+            <p>Caf&eacute; &amp; <img src=\"a.png\" alt=\"a picture\"> bar</p></body></html>";
+        assert_eq!(from_html(html), "Title\n\nOne bold word.\n\nLine\nAnother\nbreak\n\n\
+            Caf&eacute; & a picture bar\n");
+    }
+
+    #[test]
+    fn lists_and_tables() {
+        let html = "<ul><li>one</li><li>two <i>it</i></li></ul>\
+            <table><tr><td>a</td><td>b</td></tr><tr><th>c</th></tr></table>";
+        assert_eq!(from_html(html), "- one\n- two it\n\na b\nc\n");
+    }
+
+    #[test]
+    fn pre_keeps_its_whitespace() {
+        assert_eq!(from_html("<p>x</p><pre>  a\n    &lt;b&gt;</pre>"), "x\n\n  a\n    <b>\n");
+    }
+
+    #[test]
+    fn inline_text_of_headings() {
+        assert_eq!(inline_text("  <b>Bold</b> and <a href=\"#x\"><i>nested</i> link</a> "),
+            "Bold and nested link");
+        assert_eq!(inline_text("Line<br>break<BR/>here"), "Line break here");
+        assert_eq!(inline_text("Q&amp;A\n\t &#8212; <code>x&lt;y</code>"), "Q&A — x<y");
+        assert_eq!(inline_text("<img src=\"a.png\" alt=\"only an image\">"), "");
+    }
+}
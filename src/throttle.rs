@@ -0,0 +1,38 @@
+//! Limiting how fast requests are made, shared between all the threads making them.
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket: requests can be made at up to `rate` per second on average, with bursts of up
+/// to a second's worth after a quiet spell.
+pub struct RateLimiter {
+    rate: f64,
+    state: Mutex<(f64, Instant)>, // tokens available, and when that was worked out
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64) -> Self {
+        Self { rate, state: Mutex::new((rate.max(1.), Instant::now())) }
+    }
+
+    /// Block until a request can be made.
+    pub fn wait(&self) {
+        loop {
+            let delay = {
+                let mut state = self.state.lock().unwrap();
+                let (tokens, then) = &mut *state;
+                let now = Instant::now();
+                *tokens = (*tokens + now.duration_since(*then).as_secs_f64() * self.rate)
+                    .min(self.rate.max(1.));
+                *then = now;
+                if *tokens >= 1. {
+                    *tokens -= 1.;
+                    return;
+                }
+                Duration::from_secs_f64((1. - *tokens) / self.rate)
+            };
+            thread::sleep(delay);
+        }
+    }
+}
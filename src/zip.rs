@@ -0,0 +1,270 @@
+//! A minimal ZIP archive writer. Entries are stored uncompressed, which is all EPUB containers
+//! need, and keeps this small enough to not bother with a dependency. Archives with more than
+//! 65535 files or over 4 GiB use the ZIP64 extensions.
+
+use chrono::{Datelike, Timelike};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+/// Table for the CRC-32 used by ZIP (the IEEE polynomial, reflected).
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc = CRC_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+}
+
+/// What goes in a 32-bit size or offset field that doesn't fit, meaning the real value is in the
+/// ZIP64 extra field.
+const ZIP64_U32: u32 = 0xFFFF_FFFF;
+const ZIP64_U16: u16 = 0xFFFF;
+
+/// The ZIP64 extra field, holding whichever of the given values are too big for their usual field.
+fn zip64_extra(values: &[u64]) -> Vec<u8> {
+    let values = values.iter().filter(|&&v| v >= ZIP64_U32 as u64).collect::<Vec<_>>();
+    if values.is_empty() {
+        return vec![];
+    }
+    let mut extra = vec![];
+    extra.extend_from_slice(&1u16.to_le_bytes()); // header ID
+    extra.extend_from_slice(&(values.len() as u16 * 8).to_le_bytes());
+    for v in values {
+        extra.extend_from_slice(&v.to_le_bytes());
+    }
+    extra
+}
+
+/// A value for a 32-bit field, or the marker saying it's in the ZIP64 extra field.
+fn u32_or_zip64(v: u64) -> u32 {
+    u32::try_from(v).ok().filter(|&v| v != ZIP64_U32).unwrap_or(ZIP64_U32)
+}
+
+/// The "version needed to extract": 2.0 for plain stored files, 4.5 for ZIP64.
+fn version_needed(zip64: bool) -> u16 {
+    if zip64 { 45 } else { 20 }
+}
+
+pub struct ZipWriter<W: Write> {
+    out: W,
+    offset: u64,
+    entries: Vec<Entry>,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+impl<W: Write> ZipWriter<W> {
+    /// Start an archive whose files are all stamped with the given time, or with the earliest
+    /// time ZIP can represent if there isn't one.
+    pub fn new(out: W, time: Option<chrono::NaiveDateTime>) -> Self {
+        let (dos_time, dos_date) = match time {
+            Some(t) => (
+                ((t.hour() << 11) | (t.minute() << 5) | (t.second() / 2)) as u16,
+                (((t.year().max(1980) - 1980) as u32) << 9 | (t.month() << 5) | t.day()) as u16,
+            ),
+            None => (0, (1 << 5) | 1), // 1980-01-01 00:00
+        };
+        Self { out, offset: 0, entries: vec![], dos_time, dos_date }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// Add a file to the archive.
+    pub fn add(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let name_len = u16::try_from(name.len()).map_err(|_| {
+            io::Error::other(format!("file name too long for a zip archive: {}", name))
+        })?;
+        let offset = self.offset;
+        let size = data.len() as u64;
+        let crc = crc32(data);
+        // Both sizes go in the local header's extra field, if either does.
+        let extra = zip64_extra(&[size, size]);
+
+        let mut header = vec![];
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        header.extend_from_slice(&version_needed(!extra.is_empty()).to_le_bytes());
+        header.extend_from_slice(&0x0800u16.to_le_bytes()); // flags: UTF-8 names
+        header.extend_from_slice(&0u16.to_le_bytes()); // method: stored
+        header.extend_from_slice(&self.dos_time.to_le_bytes());
+        header.extend_from_slice(&self.dos_date.to_le_bytes());
+        header.extend_from_slice(&crc.to_le_bytes());
+        header.extend_from_slice(&u32_or_zip64(size).to_le_bytes()); // compressed size
+        header.extend_from_slice(&u32_or_zip64(size).to_le_bytes()); // uncompressed size
+        header.extend_from_slice(&name_len.to_le_bytes());
+        header.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&extra);
+        self.write(&header)?;
+        self.write(data)?;
+
+        self.entries.push(Entry { name: name.to_owned(), crc, size, offset });
+        Ok(())
+    }
+
+    /// Write the central directory and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let cd_start = self.offset;
+        let mut cd = vec![];
+        let mut any_zip64 = false;
+        for entry in &self.entries {
+            let extra = zip64_extra(&[entry.size, entry.size, entry.offset]);
+            any_zip64 |= !extra.is_empty();
+            cd.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            cd.extend_from_slice(&version_needed(!extra.is_empty()).to_le_bytes()); // made by
+            cd.extend_from_slice(&version_needed(!extra.is_empty()).to_le_bytes());
+            cd.extend_from_slice(&0x0800u16.to_le_bytes());
+            cd.extend_from_slice(&0u16.to_le_bytes());
+            cd.extend_from_slice(&self.dos_time.to_le_bytes());
+            cd.extend_from_slice(&self.dos_date.to_le_bytes());
+            cd.extend_from_slice(&entry.crc.to_le_bytes());
+            cd.extend_from_slice(&u32_or_zip64(entry.size).to_le_bytes());
+            cd.extend_from_slice(&u32_or_zip64(entry.size).to_le_bytes());
+            cd.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            cd.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            cd.extend_from_slice(&0u16.to_le_bytes()); // comment length
+            cd.extend_from_slice(&0u16.to_le_bytes()); // disk number
+            cd.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+            cd.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+            cd.extend_from_slice(&u32_or_zip64(entry.offset).to_le_bytes());
+            cd.extend_from_slice(entry.name.as_bytes());
+            cd.extend_from_slice(&extra);
+        }
+        let cd_size = cd.len() as u64;
+        let count = self.entries.len() as u64;
+        let count16 = u16::try_from(count).ok().filter(|&n| n != ZIP64_U16).unwrap_or(ZIP64_U16);
+
+        if any_zip64 || count16 == ZIP64_U16
+            || u32_or_zip64(cd_size) == ZIP64_U32 || u32_or_zip64(cd_start) == ZIP64_U32
+        {
+            // The ZIP64 end of central directory record, and the locator pointing at it.
+            let record_start = cd_start + cd_size;
+            cd.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+            cd.extend_from_slice(&44u64.to_le_bytes()); // size of the rest of the record
+            cd.extend_from_slice(&45u16.to_le_bytes()); // version made by
+            cd.extend_from_slice(&45u16.to_le_bytes()); // version needed
+            cd.extend_from_slice(&0u32.to_le_bytes()); // this disk
+            cd.extend_from_slice(&0u32.to_le_bytes()); // disk with central directory
+            cd.extend_from_slice(&count.to_le_bytes());
+            cd.extend_from_slice(&count.to_le_bytes());
+            cd.extend_from_slice(&cd_size.to_le_bytes());
+            cd.extend_from_slice(&cd_start.to_le_bytes());
+
+            cd.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+            cd.extend_from_slice(&0u32.to_le_bytes()); // disk with the ZIP64 record
+            cd.extend_from_slice(&record_start.to_le_bytes());
+            cd.extend_from_slice(&1u32.to_le_bytes()); // total disks
+        }
+
+        cd.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        cd.extend_from_slice(&0u16.to_le_bytes()); // this disk
+        cd.extend_from_slice(&0u16.to_le_bytes()); // disk with central directory
+        cd.extend_from_slice(&count16.to_le_bytes());
+        cd.extend_from_slice(&count16.to_le_bytes());
+        cd.extend_from_slice(&u32_or_zip64(cd_size).to_le_bytes());
+        cd.extend_from_slice(&u32_or_zip64(cd_start).to_le_bytes());
+        cd.extend_from_slice(&0u16.to_le_bytes()); // comment length
+        self.write(&cd)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn u16_at(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([data[at], data[at + 1]])
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(data[at .. at + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(data[at .. at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn small_archives_are_plain_zip() {
+        let mut zip = ZipWriter::new(vec![], None);
+        zip.add("a.txt", b"hello").unwrap();
+        zip.add("b/c.txt", b"").unwrap();
+        let data = zip.finish().unwrap();
+
+        let eocd = data.len() - 22;
+        assert_eq!(u32_at(&data, eocd), 0x0605_4b50);
+        assert_eq!(u16_at(&data, eocd + 10), 2);
+        // No ZIP64 locator before it.
+        assert_ne!(u32_at(&data, eocd - 20), 0x0706_4b50);
+        assert_eq!(u16_at(&data, 4), 20);
+        assert_eq!(&data[30 .. 35], b"a.txt");
+        assert_eq!(&data[35 .. 40], b"hello");
+    }
+
+    #[test]
+    fn many_files_use_zip64() {
+        const COUNT: usize = 70_000;
+        let mut zip = ZipWriter::new(vec![], None);
+        for i in 0 .. COUNT {
+            zip.add(&format!("{}.txt", i), b"x").unwrap();
+        }
+        let data = zip.finish().unwrap();
+
+        let eocd = data.len() - 22;
+        assert_eq!(u32_at(&data, eocd), 0x0605_4b50);
+        assert_eq!(u16_at(&data, eocd + 8), 0xFFFF);
+        assert_eq!(u16_at(&data, eocd + 10), 0xFFFF);
+
+        let locator = eocd - 20;
+        assert_eq!(u32_at(&data, locator), 0x0706_4b50);
+        let record = u64_at(&data, locator + 8) as usize;
+        assert_eq!(u32_at(&data, record), 0x0606_4b50);
+        assert_eq!(u64_at(&data, record + 24), COUNT as u64);
+        assert_eq!(u64_at(&data, record + 32), COUNT as u64);
+        let cd_size = u64_at(&data, record + 40) as usize;
+        let cd_start = u64_at(&data, record + 48) as usize;
+        assert_eq!(cd_start + cd_size, record);
+        assert_eq!(u32_at(&data, cd_start), 0x0201_4b50);
+    }
+
+    #[test]
+    fn zip64_extra_only_has_what_doesnt_fit() {
+        assert!(zip64_extra(&[5, 5, 10]).is_empty());
+        let extra = zip64_extra(&[5, 5, 1 << 32]);
+        assert_eq!(extra, [&[1, 0, 8, 0][..], &(1u64 << 32).to_le_bytes()].concat());
+        assert_eq!(u32_or_zip64(1 << 32), ZIP64_U32);
+        assert_eq!(u32_or_zip64(ZIP64_U32 as u64), ZIP64_U32);
+        assert_eq!(u32_or_zip64(7), 7);
+    }
+}